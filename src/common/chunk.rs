@@ -1,6 +1,6 @@
-use std::{fmt, vec};
+use std::{fmt, rc::Rc, vec};
 
-use super::value::Value;
+use super::value::{HeapValue, Value};
 
 pub struct FmtWriter<W: std::io::Write>(pub W);
 
@@ -14,7 +14,7 @@ impl<W: std::io::Write> std::fmt::Write for FmtWriter<W> {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OpCode {
     Return = 0,
@@ -32,10 +32,40 @@ pub enum OpCode {
     Equal = 12,
     Greater = 13,
     Less = 14,
+    Print = 15,
+    Pop = 16,
+    DefineGlobal = 17,
+    GetGlobal = 18,
+    SetGlobal = 19,
+    GetLocal = 20,
+    SetLocal = 21,
+    Jump = 22,
+    JumpIfFalse = 23,
+    Loop = 24,
+    Call = 25,
+    Closure = 26,
+    GetUpvalue = 27,
+    SetUpvalue = 28,
+    CloseUpvalue = 29,
+    Class = 30,
+    GetProperty = 31,
+    SetProperty = 32,
+    Method = 33,
+    Invoke = 34,
+    Inherit = 35,
+    GetSuper = 36,
+    SuperInvoke = 37,
+    Modulo = 38,
+    Power = 39,
+    FloorDivide = 40,
 }
-impl From<u8> for OpCode {
-    fn from(value: u8) -> Self {
-        match value {
+
+impl TryFrom<u8> for OpCode {
+    // The unrecognized byte.
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
             0 => OpCode::Return,
             1 => OpCode::Constant,
             2 => OpCode::ConstantLong,
@@ -51,7 +81,52 @@ impl From<u8> for OpCode {
             12 => OpCode::Equal,
             13 => OpCode::Greater,
             14 => OpCode::Less,
-            unrecognized => panic!("Unrecognized opcode {}", unrecognized),
+            15 => OpCode::Print,
+            16 => OpCode::Pop,
+            17 => OpCode::DefineGlobal,
+            18 => OpCode::GetGlobal,
+            19 => OpCode::SetGlobal,
+            20 => OpCode::GetLocal,
+            21 => OpCode::SetLocal,
+            22 => OpCode::Jump,
+            23 => OpCode::JumpIfFalse,
+            24 => OpCode::Loop,
+            25 => OpCode::Call,
+            26 => OpCode::Closure,
+            27 => OpCode::GetUpvalue,
+            28 => OpCode::SetUpvalue,
+            29 => OpCode::CloseUpvalue,
+            30 => OpCode::Class,
+            31 => OpCode::GetProperty,
+            32 => OpCode::SetProperty,
+            33 => OpCode::Method,
+            34 => OpCode::Invoke,
+            35 => OpCode::Inherit,
+            36 => OpCode::GetSuper,
+            37 => OpCode::SuperInvoke,
+            38 => OpCode::Modulo,
+            39 => OpCode::Power,
+            40 => OpCode::FloorDivide,
+            unrecognized => return Err(unrecognized),
+        })
+    }
+}
+
+impl OpCode {
+    // Applies an arithmetic instruction to two numbers, or returns None for
+    // any other instruction. Both the VM and constant folding use this, so
+    // they can't disagree.
+    pub fn arithmetic(self, a: f64, b: f64) -> Option<f64> {
+        match self {
+            OpCode::Add => Some(a + b),
+            OpCode::Subtract => Some(a - b),
+            OpCode::Multiply => Some(a * b),
+            OpCode::Divide => Some(a / b),
+            // Floored, so the result takes the sign of the divisor.
+            OpCode::Modulo => Some(a - b * (a / b).floor()),
+            OpCode::Power => Some(a.powf(b)),
+            OpCode::FloorDivide => Some((a / b).floor()),
+            _ => None,
         }
     }
 }
@@ -59,117 +134,461 @@ impl From<u8> for OpCode {
 #[derive(Copy, Clone, Debug)]
 pub struct ConstantIdx(pub u32);
 
+// The part of the source an instruction was compiled from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub line: u32,
+    // 1-based, counted in characters.
+    pub column: u32,
+    // Byte range in the source.
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Span {
+    // A span from the start of this one to the end of `end`.
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            offset: self.offset,
+            length: (end.offset + end.length).saturating_sub(self.offset),
+        }
+    }
+}
+
+// Maps code offsets to the spans they were compiled from. A line covers
+// many instructions, so lines are run-length encoded on their own. Spans
+// change with nearly every instruction, so consecutive bytes with the same
+// span share a run, and runs are packed as small deltas. Together they take
+// a few bytes per instruction.
+#[derive(Debug, Default)]
+pub struct DebugInfo {
+    // Code offset each line starts at, in ascending order, and the line.
+    lines: Vec<(u32, u32)>,
+    // For each run: its start, column, source offset and length, as LEB128
+    // varints. Starts and source offsets are deltas from the previous run,
+    // and source offsets are zigzag encoded since they can move backwards.
+    spans: Vec<u8>,
+    // The last run, which new bytes either extend or follow.
+    last: Option<(usize, Span)>,
+}
+
+impl DebugInfo {
+    // Rebuilds debug info from runs returned by `runs`, e.g. when loading a
+    // compiled script.
+    pub fn from_runs(runs: Vec<(usize, Span)>) -> Self {
+        let mut debug_info = DebugInfo::default();
+        for (start, span) in runs {
+            debug_info.push(start, span);
+        }
+        debug_info
+    }
+
+    fn push(&mut self, offset: usize, span: Span) {
+        let (last_start, last_offset) = match self.last {
+            Some((_, last)) if last == span => return,
+            Some((start, last)) => (start, last.offset),
+            None => (0, 0),
+        };
+        if self.lines.last().is_none_or(|(_, line)| *line != span.line) {
+            self.lines.push((offset as u32, span.line));
+        }
+        write_varint(&mut self.spans, (offset - last_start) as u64);
+        write_varint(&mut self.spans, span.column as u64);
+        let delta = span.offset as i64 - last_offset as i64;
+        write_varint(&mut self.spans, ((delta << 1) ^ (delta >> 63)) as u64);
+        write_varint(&mut self.spans, span.length as u64);
+        self.last = Some((offset, span));
+    }
+
+    pub fn span(&self, offset: usize) -> Span {
+        self.runs()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map(|(_, span)| span)
+            .expect("No debug info for offset.")
+    }
+
+    pub fn line(&self, offset: usize) -> u32 {
+        let run = self.lines.partition_point(|(start, _)| *start as usize <= offset);
+        self.lines[run - 1].1
+    }
+
+    // Each run's start and span, in order.
+    pub fn runs(&self) -> impl Iterator<Item = (usize, Span)> + '_ {
+        Runs::new(self).map(|(_, start, span)| (start, span))
+    }
+
+    // Bytes used, for pacing garbage collection.
+    pub fn size(&self) -> usize {
+        std::mem::size_of_val(self.lines.as_slice()) + self.spans.len()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        // Cut the encoded runs at the first one starting at `len` or later.
+        let mut cut = (self.spans.len(), self.last);
+        let mut previous = None;
+        for (position, start, span) in Runs::new(self) {
+            if start >= len {
+                cut = (position, previous);
+                break;
+            }
+            previous = Some((start, span));
+        }
+        self.spans.truncate(cut.0);
+        self.last = cut.1;
+        let line = self.lines.partition_point(|(start, _)| (*start as usize) < len);
+        self.lines.truncate(line);
+    }
+}
+
+// Decodes `DebugInfo::spans`, yielding where each run is encoded along with
+// its start and span.
+struct Runs<'a> {
+    debug_info: &'a DebugInfo,
+    position: usize,
+    start: usize,
+    offset: usize,
+}
+
+impl<'a> Runs<'a> {
+    fn new(debug_info: &'a DebugInfo) -> Self {
+        Runs {
+            debug_info,
+            position: 0,
+            start: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl Iterator for Runs<'_> {
+    type Item = (usize, usize, Span);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let spans = &self.debug_info.spans;
+        if self.position == spans.len() {
+            return None;
+        }
+        let position = self.position;
+        let mut read = || read_varint(spans, &mut self.position);
+        self.start += read() as usize;
+        let column = read() as u32;
+        let delta = read();
+        let length = read() as usize;
+        self.offset = (self.offset as i64 + ((delta >> 1) as i64 ^ -((delta & 1) as i64))) as usize;
+        let span = Span {
+            line: self.debug_info.line(self.start),
+            column,
+            offset: self.offset,
+            length,
+        };
+        Some((position, self.start, span))
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*position];
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<u32>,
-    pub constants: Vec<Value>,
+    pub debug_info: DebugInfo,
+    pub(crate) constants: Vec<Value>,
+    // The source the chunk was compiled from, shared by every function in
+    // the script.
+    pub source: Option<Rc<str>>,
 }
 
+// Lists every instruction, with jump targets marked by labels.
 impl fmt::Display for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut i = 0;
-        while i < self.code.len() {
-            i = self.disassemble(f, i)?;
+        let labels = self.jump_targets();
+        for instruction in self.instructions() {
+            if let Ok(label) = labels.binary_search(&instruction.offset) {
+                writeln!(f, "L{}:", label)?;
+            }
+            self.write_instruction(f, &instruction, Some(&labels))?;
         }
         Ok(())
     }
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: vec![],
-            lines: vec![],
+            debug_info: DebugInfo::default(),
             constants: vec![],
+            source: None,
         }
     }
 
-    pub fn add_constant(&mut self, constant: Value) -> ConstantIdx {
-        #[cfg(debug_assertions)]
-        {
-            println!(
-                "Adding constant {} ({})",
-                constant.clone(),
-                self.constants.len()
-            );
-        }
+    pub fn span(&self, offset: usize) -> Span {
+        self.debug_info.span(offset)
+    }
+
+    pub fn line(&self, offset: usize) -> u32 {
+        self.debug_info.line(offset)
+    }
+
+    pub(crate) fn add_constant(&mut self, constant: Value) -> ConstantIdx {
         self.constants.push(constant);
 
         ConstantIdx((self.constants.len() - 1) as u32)
     }
 
-    pub fn add_code_op(&mut self, code: OpCode, line: u32) {
-        #[cfg(debug_assertions)]
-        println!("Adding op {:?}", code);
-
+    pub fn add_code_op(&mut self, code: OpCode, span: Span) {
         self.code.push(code as u8);
-        self.lines.push(line);
+        self.debug_info.push(self.code.len() - 1, span);
     }
 
-    // TODO: refactor to combine with add_code_contant_long?
-    pub fn add_code_constant(&mut self, constant: ConstantIdx, line: u32) {
-        #[cfg(debug_assertions)]
-        println!("Pushing constant {:?}", constant);
+    pub fn add_code_byte(&mut self, byte: u8, span: Span) {
+        self.code.push(byte);
+        self.debug_info.push(self.code.len() - 1, span);
+    }
 
+    // Drops the code from `len` on, e.g. to replace it with something
+    // shorter.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.debug_info.truncate(len);
+    }
+
+    // TODO: refactor to combine with add_code_contant_long?
+    pub fn add_code_constant(&mut self, constant: ConstantIdx, span: Span) {
         assert!(
             constant.0 <= 255,
             "Single operand (short) constant index must be < 256."
         );
         let bytes = constant.0.to_le_bytes();
         self.code.push(bytes[0]);
-        for _ in 0..2 {
-            self.lines.push(line);
-        }
+        self.debug_info.push(self.code.len() - 1, span);
     }
 
-    pub fn add_code_constant_long(&mut self, constant: ConstantIdx, line: u32) {
+    pub fn add_code_constant_long(&mut self, constant: ConstantIdx, span: Span) {
         let bytes = constant.0.to_le_bytes();
         assert!(
             constant.0 <= 16777216,
             "Double operand (long) constant index must be < 16777216."
         );
+        self.debug_info.push(self.code.len(), span);
         self.code.extend(&bytes[0..3]);
+    }
+
+    // Decodes the instruction starting at `offset`, which must be valid.
+    pub fn instruction(&self, offset: usize) -> Instruction {
+        self.decode(offset)
+            .unwrap_or_else(|message| panic!("Invalid instruction at {}: {}", offset, message))
+    }
 
-        for _ in 0..5 {
-            self.lines.push(line);
+    // Decodes the instruction starting at `offset`, or explains why the
+    // bytes there aren't a complete instruction.
+    pub fn decode(&self, offset: usize) -> Result<Instruction, String> {
+        let opcode = OpCode::try_from(self.code[offset])
+            .map_err(|byte| format!("Unknown opcode {}.", byte))?;
+        // Operands are only read once the instruction's length is known, so
+        // a truncated instruction reads zeroes and is rejected below.
+        let byte = |n: usize| self.code.get(offset + n).copied().unwrap_or(0);
+        let (operand, length) = match opcode {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetSuper => (Operand::Constant(byte(1) as usize), 2),
+            OpCode::ConstantLong => {
+                let idx = u32::from_le_bytes([byte(1), byte(2), byte(3), 0]);
+                (Operand::Constant(idx as usize), 4)
+            }
+            OpCode::GetLocal | OpCode::SetLocal | OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                (Operand::Slot(byte(1)), 2)
+            }
+            OpCode::Call => (Operand::ArgCount(byte(1)), 2),
+            OpCode::Invoke | OpCode::SuperInvoke => (
+                Operand::Invoke {
+                    constant: byte(1) as usize,
+                    arg_count: byte(2),
+                },
+                3,
+            ),
+            OpCode::Closure => {
+                let constant = byte(1) as usize;
+                let upvalue_count = match self.constants.get(constant) {
+                    Some(Value::Object(obj)) => match &obj.value {
+                        HeapValue::Function(function) => function.upvalue_count as usize,
+                        _ => return Err("Closure operand isn't a function.".to_owned()),
+                    },
+                    _ => return Err("Closure operand isn't a function.".to_owned()),
+                };
+                let upvalues = (0..upvalue_count)
+                    .map(|n| (byte(2 + n * 2) == 1, byte(3 + n * 2)))
+                    .collect();
+                (Operand::Closure { constant, upvalues }, 2 + upvalue_count * 2)
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = u16::from_be_bytes([byte(1), byte(2)]) as usize;
+                let target = match opcode {
+                    OpCode::Loop => (offset + 3)
+                        .checked_sub(jump)
+                        .ok_or("Loop jumps before the start of the code.")?,
+                    _ => offset + 3 + jump,
+                };
+                (Operand::Jump(target), 3)
+            }
+            _ => (Operand::None, 1),
+        };
+        if offset + length > self.code.len() {
+            return Err(format!("{:?} is missing its operands.", opcode));
         }
+        Ok(Instruction {
+            offset,
+            opcode,
+            operand,
+            length,
+        })
+    }
+
+    // Every instruction in the chunk, in order.
+    pub fn instructions(&self) -> impl Iterator<Item = Instruction> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if offset >= self.code.len() {
+                return None;
+            }
+            let instruction = self.instruction(offset);
+            offset += instruction.length;
+            Some(instruction)
+        })
+    }
+
+    // The offsets jumps land on, sorted, so label `L<n>` is `targets[n]`.
+    pub fn jump_targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self
+            .instructions()
+            .filter_map(|instruction| match instruction.operand {
+                Operand::Jump(target) => Some(target),
+                _ => None,
+            })
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
     }
 
-    // Outputs the dissassembed instruction at the offset, and returs the offset of the next instruction
-    pub fn disassemble<T: std::fmt::Write>(
+    // Outputs the disassembled instruction at the offset, and returns the
+    // offset of the next instruction. Jump targets are shown as offsets since
+    // there are no labels without the rest of the chunk.
+    pub fn disassemble<T: fmt::Write>(&self, f: &mut T, offset: usize) -> Result<usize, fmt::Error> {
+        self.write_instruction(f, &self.instruction(offset), None)
+    }
+
+    fn write_instruction<T: fmt::Write>(
         &self,
         f: &mut T,
-        offset: usize,
-    ) -> Result<usize, std::fmt::Error> {
-        let mut i = offset;
-        write!(f, "{:03x}", i * 2)?;
-        if i > 0 && self.lines[i] == self.lines[i - 1] {
+        instruction: &Instruction,
+        labels: Option<&[usize]>,
+    ) -> Result<usize, fmt::Error> {
+        let offset = instruction.offset;
+        write!(f, "{:04} ", offset)?;
+        if offset > 0 && self.line(offset) == self.line(offset - 1) {
             write!(f, "   | ")?;
         } else {
-            write!(f, "{:>3} ", i)?;
+            write!(f, "{:4} ", self.line(offset))?;
         }
 
-        let opcode = OpCode::from(self.code[i]);
-        write!(f, "{:?}", opcode)?;
-        match opcode {
-            OpCode::Constant => {
-                i += 1;
-                let idx = self.code[i];
-                let constant = &self.constants[idx as usize];
-                write!(f, "     {} '{}'", idx, constant)?;
+        let name = format!("{:?}", instruction.opcode);
+        match &instruction.operand {
+            Operand::None => write!(f, "{}", name)?,
+            Operand::Constant(idx) => {
+                write!(f, "{:<16} {:4} '{}'", name, idx, self.constants[*idx])?
             }
-            OpCode::ConstantLong => {
-                i += 1;
-                let mut bytes: [u8; 4] = [0; 4];
-                bytes[0..3].copy_from_slice(&self.code[i..i + 3]);
-                let idx = u32::from_le_bytes(bytes);
-                let constant = &self.constants[idx as usize];
-                write!(f, "     {} '{}'", idx, constant)?;
-                i += 2;
+            Operand::Slot(slot) => write!(f, "{:<16} {:4}", name, slot)?,
+            Operand::ArgCount(count) => write!(f, "{:<16} {:4} args", name, count)?,
+            Operand::Invoke {
+                constant,
+                arg_count,
+            } => write!(
+                f,
+                "{:<16} {:4} '{}' ({} args)",
+                name, constant, self.constants[*constant], arg_count
+            )?,
+            Operand::Closure { constant, upvalues } => {
+                write!(f, "{:<16} {:4} '{}'", name, constant, self.constants[*constant])?;
+                for (n, (is_local, index)) in upvalues.iter().enumerate() {
+                    let kind = if *is_local { "local" } else { "upvalue" };
+                    write!(f, "\n{:04}    |   {:<16} {:4}", offset + 2 + n * 2, kind, index)?;
+                }
+            }
+            Operand::Jump(target) => {
+                match labels.and_then(|labels| labels.binary_search(target).ok()) {
+                    Some(label) => write!(f, "{:<16} -> L{} ({:04})", name, label, target)?,
+                    None => write!(f, "{:<16} -> {:04}", name, target)?,
+                }
             }
-            _ => (),
         }
         writeln!(f)?;
-        Ok(i + 1)
+        Ok(offset + instruction.length)
     }
 }
+
+// A decoded instruction and its operands.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub opcode: OpCode,
+    pub operand: Operand,
+    // Size in bytes, including operands.
+    pub length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    // Index into the chunk's constants.
+    Constant(usize),
+    // A local's stack slot or an upvalue's index.
+    Slot(u8),
+    ArgCount(u8),
+    Invoke {
+        constant: usize,
+        arg_count: u8,
+    },
+    // The function constant, then for each upvalue whether it captures a
+    // local of the enclosing function and that local's slot or upvalue index.
+    Closure {
+        constant: usize,
+        upvalues: Vec<(bool, u8)>,
+    },
+    // The absolute offset jumped to.
+    Jump(usize),
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+use std::rc::Rc;
+
+use super::chunk::Span;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrame {
+    // None for the top-level script.
+    pub function: Option<String>,
+    pub line: u32,
+}
+
+// An error raised while running a program, either by the VM itself or by
+// native code. Natives only fill in the message; the VM adds the rest when
+// it reports the error.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // Innermost call first.
+    pub frames: Vec<StackFrame>,
+    // Where the failing instruction was compiled from, and the source it
+    // points into.
+    pub span: Option<Span>,
+    pub source: Option<Rc<str>>,
+}
+
+impl RuntimeError {
+    pub fn new(message: &str) -> Self {
+        RuntimeError {
+            message: message.to_owned(),
+            frames: vec![],
+            span: None,
+            source: None,
+        }
+    }
+
+    // The source line containing the error with the failing expression
+    // underlined, e.g.
+    //
+    //   |
+    // 1 | print a + b * c;
+    //   |           ^^^^^
+    pub fn snippet(&self) -> Option<String> {
+        let span = self.span?;
+        let source = self.source.as_deref()?;
+        let line_start = source[..span.offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = source[span.offset..]
+            .find('\n')
+            .map_or(source.len(), |idx| span.offset + idx);
+        let line_number = (source[..line_start].matches('\n').count() + 1).to_string();
+
+        // Keep tabs so the carets line up with the text above them.
+        let padding: String = source[line_start..span.offset]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let span_end = (span.offset + span.length).min(line_end);
+        let underline = "^".repeat(source[span.offset..span_end].chars().count().max(1));
+
+        let gutter = " ".repeat(line_number.len());
+        Some(format!(
+            "{gutter} |\n{line_number} | {}\n{gutter} | {padding}{underline}",
+            &source[line_start..line_end],
+        ))
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(snippet) = self.snippet() {
+            write!(f, "\n{}", snippet)?;
+        }
+        for frame in &self.frames {
+            match &frame.function {
+                Some(name) => write!(f, "\n[line {}] in {}()", frame.line, name)?,
+                None => write!(f, "\n[line {}] in script", frame.line)?,
+            }
+        }
+        Ok(())
+    }
+}
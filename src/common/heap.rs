@@ -0,0 +1,216 @@
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+use super::table::{Hashable, Table};
+use super::value::{HeapValue, Obj, ObjRef, ObjUpvalue, Value};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+// Implemented by anything that can hold references to heap objects, so the
+// collector can find everything reachable from it.
+pub trait Trace {
+    fn trace(&self, heap: &mut Heap);
+}
+
+// Owns every object allocated by the VM and the compiler.
+//
+// Collection is tri-color mark and sweep: callers mark their roots (gray),
+// `trace_references` blackens gray objects by marking everything they
+// reference, and `sweep` frees whatever is left unmarked (white). The heap
+// never starts a collection on its own since it doesn't know the roots;
+// owners check `should_collect` before allocating.
+pub struct Heap {
+    objects: Option<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    gray_stack: Vec<ObjRef>,
+    stress_gc: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: None,
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            gray_stack: vec![],
+            stress_gc: false,
+        }
+    }
+
+    // Collect before every allocation. Useful for shaking out missing roots.
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn alloc(&mut self, value: HeapValue) -> ObjRef {
+        let size = std::mem::size_of::<Obj>() + value.heap_size();
+        self.bytes_allocated += size;
+
+        let obj = Box::new(Obj {
+            value,
+            next: Cell::new(self.objects),
+            is_marked: Cell::new(false),
+            size,
+        });
+        let obj = ObjRef(NonNull::from(Box::leak(obj)));
+        self.objects = Some(obj);
+        obj
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if obj.is_marked.get() {
+            return;
+        }
+        obj.is_marked.set(true);
+        self.gray_stack.push(obj);
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Object(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn is_marked(&self, obj: ObjRef) -> bool {
+        obj.is_marked.get()
+    }
+
+    pub fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            obj.value.trace(self);
+        }
+    }
+
+    // Frees every unmarked object and clears the marks on the survivors.
+    pub fn sweep(&mut self) {
+        let mut previous: Option<ObjRef> = None;
+        let mut object = self.objects;
+        while let Some(obj) = object {
+            let next = obj.next.get();
+            if obj.is_marked.get() {
+                obj.is_marked.set(false);
+                previous = Some(obj);
+            } else {
+                match previous {
+                    Some(previous) => previous.next.set(next),
+                    None => self.objects = next,
+                }
+                self.bytes_allocated -= obj.size;
+                unsafe { drop(Box::from_raw(obj.0.as_ptr())) };
+            }
+            object = next;
+        }
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let mut object = self.objects.take();
+        while let Some(obj) = object {
+            object = obj.next.get();
+            unsafe { drop(Box::from_raw(obj.0.as_ptr())) };
+        }
+    }
+}
+
+impl HeapValue {
+    // Rough size of what this object owns outside of its `Obj`, used to pace
+    // collections.
+    fn heap_size(&self) -> usize {
+        match self {
+            HeapValue::String(string) => string.len(),
+            HeapValue::Function(function) => {
+                function.chunk.code.len()
+                    + function.chunk.debug_info.size()
+                    + function.chunk.constants.len() * std::mem::size_of::<Value>()
+            }
+            HeapValue::Closure(closure) => closure.upvalues.len() * std::mem::size_of::<ObjRef>(),
+            HeapValue::Upvalue(_)
+            | HeapValue::Class(_)
+            | HeapValue::Instance(_)
+            | HeapValue::BoundMethod(_)
+            | HeapValue::Native(_) => 0,
+        }
+    }
+}
+
+impl Trace for HeapValue {
+    fn trace(&self, heap: &mut Heap) {
+        match self {
+            HeapValue::String(_) => (),
+            HeapValue::Function(function) => {
+                function.name.trace(heap);
+                for constant in &function.chunk.constants {
+                    heap.mark_value(*constant);
+                }
+            }
+            HeapValue::Closure(closure) => {
+                heap.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    heap.mark_object(*upvalue);
+                }
+            }
+            HeapValue::Upvalue(upvalue) => {
+                if let ObjUpvalue::Closed(value) = &*upvalue.borrow() {
+                    heap.mark_value(*value);
+                }
+            }
+            HeapValue::Class(class) => {
+                let class = class.borrow();
+                heap.mark_object(class.name);
+                class.methods.trace(heap);
+            }
+            HeapValue::Instance(instance) => {
+                let instance = instance.borrow();
+                heap.mark_object(instance.class);
+                instance.fields.trace(heap);
+            }
+            HeapValue::BoundMethod(bound) => {
+                heap.mark_value(bound.receiver);
+                heap.mark_object(bound.method);
+            }
+            HeapValue::Native(native) => heap.mark_object(native.name),
+        }
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(*self);
+    }
+}
+
+impl Trace for ObjRef {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(*self);
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(value) = self {
+            value.trace(heap);
+        }
+    }
+}
+
+impl<K, V> Trace for Table<K, V>
+where
+    K: Hashable + PartialEq + Clone + Trace,
+    V: Trace,
+{
+    fn trace(&self, heap: &mut Heap) {
+        for (key, value) in self.iter() {
+            key.trace(heap);
+            value.trace(heap);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 mod chunk;
-pub(super) use self::chunk::*;
+pub use self::chunk::*;
+mod error;
+pub use self::error::*;
+mod heap;
+pub use self::heap::*;
 mod value;
-pub(super) use self::value::*;
+pub use self::value::*;
 mod table;
-pub(super) use self::table::*;
+pub use self::table::*;
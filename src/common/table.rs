@@ -1,6 +1,7 @@
 pub trait Hashable {
     fn hash(&self) -> u32;
 }
+#[derive(Debug)]
 pub struct Table<K, V>
 where
     K: Hashable,
@@ -10,6 +11,7 @@ where
     entries: Vec<Option<_Entry<Entry<K, V>>>>,
 }
 
+#[derive(Debug)]
 struct Entry<K, V>
 where
     K: Hashable,
@@ -18,6 +20,7 @@ where
     value: V,
 }
 
+#[derive(Debug)]
 enum _Entry<T> {
     Tombstone,
     Some(T),
@@ -29,6 +32,7 @@ where
 {
     const MAX_LOAD: f64 = 0.75;
     const GROW_FACTOR: usize = 2;
+    const MIN_CAPACITY: usize = 8;
 
     pub fn new() -> Self {
         Table {
@@ -40,7 +44,8 @@ where
 
     pub fn set(&mut self, key: &K, value: V) -> bool {
         if (self.count + 1) as f64 > self.capacity as f64 * Self::MAX_LOAD {
-            self.grow_capacity(self.capacity * Self::GROW_FACTOR);
+            let capacity = (self.capacity * Self::GROW_FACTOR).max(Self::MIN_CAPACITY);
+            self.grow_capacity(capacity);
         }
 
         let index = Self::find_entry_idx(&self.entries, self.capacity, key);
@@ -57,6 +62,9 @@ where
     }
 
     pub fn get(&self, key: &K) -> Option<&V> {
+        if self.count == 0 {
+            return None;
+        }
         let idx = Self::find_entry_idx(&self.entries, self.capacity, key);
         match &self.entries[idx] {
             Some(_Entry::Some(entry)) => Some(&entry.value),
@@ -66,6 +74,9 @@ where
     }
 
     pub fn delete(&mut self, key: &K) -> bool {
+        if self.count == 0 {
+            return false;
+        }
         let idx = Self::find_entry_idx(&self.entries, self.capacity, key);
         let exists = matches!(self.entries[idx], Some(_Entry::Some(_)));
 
@@ -76,8 +87,61 @@ where
         exists
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Some(_Entry::Some(entry)) => Some((&entry.key, &entry.value)),
+            _ => None,
+        })
+    }
+
+    pub fn add_all(&self, to: &mut Table<K, V>)
+    where
+        V: Clone,
+    {
+        for (key, value) in self.iter() {
+            to.set(key, value.clone());
+        }
+    }
+
+    // Finds a key by its hash and a custom comparison, for lookups where
+    // building the key itself is the expensive part (e.g. interning).
+    pub fn find_key<F>(&self, hash: u32, is_key: F) -> Option<&K>
+    where
+        F: Fn(&K) -> bool,
+    {
+        if self.count == 0 {
+            return None;
+        }
+        let mut index = hash as usize % self.capacity;
+        loop {
+            match &self.entries[index] {
+                None => return None,
+                Some(_Entry::Some(entry)) if entry.key.hash() == hash && is_key(&entry.key) => {
+                    return Some(&entry.key)
+                }
+                _ => (),
+            }
+
+            index = (index + 1) % self.capacity;
+        }
+    }
+
+    // Deletes every entry for which `keep` returns false.
+    pub fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        for entry in self.entries.iter_mut() {
+            if let Some(_Entry::Some(Entry { key, value })) = entry {
+                if !keep(key, value) {
+                    *entry = Some(_Entry::Tombstone);
+                }
+            }
+        }
+    }
+
     fn find_entry_idx(
-        entries: &Vec<Option<_Entry<Entry<K, V>>>>,
+        entries: &[Option<_Entry<Entry<K, V>>>],
         capacity: usize,
         key: &K,
     ) -> usize {
@@ -93,7 +157,7 @@ where
                     }
                 }
                 Some(_Entry::Some(entry)) if entry.key.eq(key) => return index,
-                Some(_Entry::Tombstone) => tombstone_idx = Some(index),
+                Some(_Entry::Tombstone) if tombstone_idx.is_none() => tombstone_idx = Some(index),
                 _ => (),
             }
 
@@ -103,16 +167,15 @@ where
 
     fn grow_capacity(&mut self, capacity: usize) {
         let mut entries: Vec<Option<_Entry<Entry<K, V>>>> = Vec::with_capacity(capacity);
-        for i in 0..capacity {
-            entries[i] = None;
-        }
+        entries.resize_with(capacity, || None);
 
+        // Tombstones are dropped while rehashing, so the count is rebuilt.
+        self.count = 0;
         for i in 0..self.capacity {
-            if let Some(entry) = self.entries[i].take() {
-                if let _Entry::Some(entry) = entry {
-                    let idx = Self::find_entry_idx(&entries, capacity, &entry.key);
-                    entries[idx] = Some(_Entry::Some(entry));
-                }
+            if let Some(_Entry::Some(entry)) = self.entries[i].take() {
+                let idx = Self::find_entry_idx(&entries, capacity, &entry.key);
+                entries[idx] = Some(_Entry::Some(entry));
+                self.count += 1;
             }
         }
 
@@ -1,13 +1,19 @@
 use std::alloc::{alloc, Layout};
+use std::cell::{Cell, RefCell};
 use std::fmt;
+use std::ptr::NonNull;
 
-use super::table::Hashable;
+use super::chunk::Chunk;
+use super::table::{Hashable, Table};
+use super::error::RuntimeError;
+use crate::host;
+use crate::vm::VM;
 
-#[derive(Clone, Debug)]
-pub enum Value {
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Value {
     Double(f64),
     Boolean(bool),
-    Object(Obj),
+    Object(ObjRef),
     Nil,
 }
 
@@ -20,6 +26,25 @@ impl Value {
             Value::Nil => true,
         }
     }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Double(val) => Some(*val),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value::Double(val)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        Value::Boolean(val)
+    }
 }
 
 impl fmt::Display for Value {
@@ -27,7 +52,7 @@ impl fmt::Display for Value {
         match self {
             Value::Double(val) => write!(f, "{}", val),
             Value::Boolean(val) => write!(f, "{}", val),
-            Value::Object(val) => write!(f, "{}", val),
+            Value::Object(val) => write!(f, "{}", val.value),
             Value::Nil => write!(f, "nil"),
         }
     }
@@ -57,29 +82,175 @@ impl std::ops::Neg for Value {
     }
 }
 
-#[derive(Clone, Debug)]
+// A heap allocation owned by the garbage collector. Every object is threaded
+// onto the heap's `objects` list through `next`.
 pub struct Obj {
     pub value: HeapValue,
-    pub next: Option<*const Obj>,
+    pub(super) next: Cell<Option<ObjRef>>,
+    pub(super) is_marked: Cell<bool>,
+    // Bytes accounted against the heap when this object was allocated.
+    pub(super) size: usize,
 }
 
-impl PartialEq for Obj {
-    fn eq(&self, other: &Obj) -> bool {
-        match (&self.value, &other.value) {
-            (HeapValue::String(a), HeapValue::String(b)) => a.eq(b),
-        }
+// Handle to an object on the garbage collected heap. Handles are only valid
+// while the object is reachable from a GC root.
+#[derive(Clone, Copy)]
+pub(crate) struct ObjRef(pub(super) NonNull<Obj>);
+
+impl std::ops::Deref for ObjRef {
+    type Target = Obj;
+
+    #[inline(always)]
+    fn deref(&self) -> &Obj {
+        unsafe { self.0.as_ref() }
     }
 }
 
-impl fmt::Display for Obj {
+impl fmt::Debug for ObjRef {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.value)
+        write!(f, "ObjRef({:p})", self.0)
+    }
+}
+
+// Strings are interned, so identity is equality for every object type.
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &ObjRef) -> bool {
+        self.0 == other.0
+    }
+}
+
+// Only string objects are used as table keys.
+impl Hashable for ObjRef {
+    #[inline(always)]
+    fn hash(&self) -> u32 {
+        self.as_string().hash()
     }
 }
 
-#[derive(Clone, Debug)]
+// Accessors for handles whose object type is known from context, such as a
+// closure's function. They panic on a type mismatch.
+impl ObjRef {
+    pub fn as_string(&self) -> &BoxedObjString {
+        match &self.value {
+            HeapValue::String(string) => string,
+            _ => panic!("Expected string object."),
+        }
+    }
+
+    pub fn as_function(&self) -> &ObjFunction {
+        match &self.value {
+            HeapValue::Function(function) => function,
+            _ => panic!("Expected function object."),
+        }
+    }
+
+    pub fn as_closure(&self) -> &ObjClosure {
+        match &self.value {
+            HeapValue::Closure(closure) => closure,
+            _ => panic!("Expected closure object."),
+        }
+    }
+
+    pub fn as_upvalue(&self) -> &RefCell<ObjUpvalue> {
+        match &self.value {
+            HeapValue::Upvalue(upvalue) => upvalue,
+            _ => panic!("Expected upvalue object."),
+        }
+    }
+
+    pub fn as_class(&self) -> &RefCell<ObjClass> {
+        match &self.value {
+            HeapValue::Class(class) => class,
+            _ => panic!("Expected class object."),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum HeapValue {
     String(BoxedObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(RefCell<ObjUpvalue>),
+    Class(RefCell<ObjClass>),
+    Instance(RefCell<ObjInstance>),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+// How deeply function declarations may nest inside the script. Serializing
+// and disassembling walk nested functions recursively, so the compiler and
+// the bytecode loader both enforce this.
+pub(crate) const FUNCTION_DEPTH_MAX: usize = 256;
+
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: u8,
+    pub upvalue_count: u8,
+    pub chunk: Chunk,
+    // A HeapValue::String, or None for the top-level script.
+    pub name: Option<ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct ObjClosure {
+    // Always a HeapValue::Function.
+    pub function: ObjRef,
+    // Each entry is a HeapValue::Upvalue.
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable. It points at the variable's stack slot while the
+// variable is still live on the stack and takes ownership of the value once
+// the slot is popped.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    // Always a HeapValue::String.
+    pub name: ObjRef,
+    // Method closures keyed by name.
+    pub methods: Table<ObjRef, ObjRef>,
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    // Always a HeapValue::Class.
+    pub class: ObjRef,
+    pub fields: Table<ObjRef, Value>,
+}
+
+// A method closure paired with the instance it was accessed on, so `this`
+// still refers to that instance when it's called later.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A function implemented in Rust. It receives the VM and the call's
+// arguments, which are already checked against `arity`.
+pub type NativeFn = fn(&mut VM, &[host::Value]) -> Result<host::Value, RuntimeError>;
+
+#[derive(Debug)]
+pub struct ObjNative {
+    // Always a HeapValue::String.
+    pub name: ObjRef,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name.as_string().as_str()),
+            None => write!(f, "<script>"),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -95,10 +266,13 @@ pub struct ObjString {
 }
 
 impl ObjString {
+    // Must agree with the layout Rust computes for a `Box<ObjString>` of
+    // this length, since that's what is used to free it.
     fn get_layout(length: usize) -> (Layout, usize) {
-        Layout::array::<u8>(length)
+        let (layout, arr_base) = Layout::array::<u8>(length)
             .and_then(|layout| Layout::new::<Inner>().extend(layout))
-            .unwrap()
+            .unwrap();
+        (layout.pad_to_align(), arr_base)
     }
 
     pub fn as_str(&self) -> &str {
@@ -123,11 +297,11 @@ impl ObjString {
 pub struct BoxedObjString(Box<ObjString>);
 
 impl BoxedObjString {
-    pub fn of_ref(source: &String) -> Self {
+    pub fn of_ref(source: &str) -> Self {
         let byte_array = source.as_bytes();
         let inner = Inner {
             length: byte_array.len(),
-            hash: ObjString::calc_hash(source.as_str()),
+            hash: ObjString::calc_hash(source),
         };
         let (layout, arr_base) = ObjString::get_layout(inner.length);
         let ptr = unsafe { alloc(layout) };
@@ -143,8 +317,7 @@ impl BoxedObjString {
         }
         unsafe {
             Self(Box::from_raw(
-                std::ptr::slice_from_raw_parts_mut(ptr as *mut usize, layout.size())
-                    as *mut ObjString,
+                std::ptr::slice_from_raw_parts_mut(ptr, inner.length) as *mut ObjString,
             ))
         }
     }
@@ -156,33 +329,11 @@ impl BoxedObjString {
     pub fn len(&self) -> usize {
         self.0.len()
     }
-    pub fn of(source: String) -> Self {
-        Self::of_ref(&source)
-    }
-}
 
-impl Clone for BoxedObjString {
-    fn clone(&self) -> Self {
-        let (layout, arr_base) = Layout::array::<u8>(self.0.inner.length)
-            .and_then(|layout| Layout::new::<Inner>().extend(layout))
-            .unwrap();
-        let ptr = unsafe { alloc(layout) };
-        if ptr.is_null() {
-            panic!("Failed to allocate ObjString");
-        }
-        unsafe {
-            ptr.cast::<Inner>().write(self.0.inner);
-            let tmp_ptr = ptr.cast::<u8>().add(arr_base);
-            for i in 0..(self.0.inner.length) {
-                tmp_ptr.add(i).write(self.0.data[i]);
-            }
-        }
-        unsafe {
-            Self(Box::from_raw(
-                std::ptr::slice_from_raw_parts_mut(ptr as *mut usize, layout.size())
-                    as *mut ObjString,
-            ))
-        }
+    // The hash a string with these contents has, for looking it up in the
+    // intern table before allocating it.
+    pub fn hash_str(source: &str) -> u32 {
+        ObjString::calc_hash(source)
     }
 }
 
@@ -193,23 +344,23 @@ impl Hashable for BoxedObjString {
     }
 }
 
-impl PartialEq for BoxedObjString {
-    fn eq(&self, other: &Self) -> bool {
-        unsafe {
-            self.0.inner.length == other.0.inner.length
-                && std::slice::from_raw_parts(self.0.data.as_ptr(), self.0.inner.length).eq(
-                    std::slice::from_raw_parts(other.0.data.as_ptr(), other.0.inner.length),
-                )
-        }
-    }
-}
-
 impl fmt::Display for HeapValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             HeapValue::String(s) => {
                 write!(f, "{}", s.as_str())
             }
+            HeapValue::Function(function) => write!(f, "{}", function),
+            HeapValue::Closure(closure) => write!(f, "{}", closure.function.value),
+            HeapValue::Upvalue(_) => write!(f, "upvalue"),
+            HeapValue::Class(class) => write!(f, "{}", class.borrow().name.as_string().as_str()),
+            HeapValue::Instance(instance) => {
+                let class = instance.borrow().class;
+                let class = class.as_class().borrow();
+                write!(f, "{} instance", class.name.as_string().as_str())
+            }
+            HeapValue::BoundMethod(bound) => write!(f, "{}", bound.method.value),
+            HeapValue::Native(_) => write!(f, "<native fn>"),
         }
     }
 }
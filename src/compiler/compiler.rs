@@ -1,22 +1,194 @@
 use crate::{
-    common::Chunk,
-    common::{BoxedObjString, HeapValue, Obj, OpCode, Value},
+    common::{Chunk, ConstantIdx, Span},
+    common::{Heap, HeapValue, ObjFunction, ObjRef, OpCode, Trace, Value, FUNCTION_DEPTH_MAX},
     compiler::scanner::Scanner,
-    vm::InterpretError,
+    vm::VM,
 };
-use std::{cell::RefCell, io::Write};
+use std::cell::RefCell;
 use std::{rc::Rc, str::FromStr};
 
-use super::{Token, TokenType};
+use super::{Diagnostic, ErrorCode, ErrorToken, Severity, Token, TokenType};
 
-struct Parser {
+struct Parser<'vm> {
+    // Objects created while compiling live on the VM's heap.
+    vm: &'vm mut VM,
     // FIXME: can we avoid doing this?
     scanner: Rc<RefCell<Scanner>>,
-    chunk: Rc<RefCell<Chunk>>,
     current: Token,
     previous: Token,
-    had_error: bool,
+    compiler: Compiler,
+    // Innermost class last.
+    class_compilers: Vec<ClassCompiler>,
+    source: Rc<str>,
+    // Start of the expression whose infix rule is being compiled, i.e. the
+    // left operand.
+    expression_start: Span,
+    // Where the left operand's code starts in the current chunk.
+    expression_code_start: usize,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
+    mode: CompileMode,
+}
+
+const LOCALS_MAX: usize = u8::MAX as usize + 1;
+
+const UPVALUES_MAX: usize = u8::MAX as usize + 1;
+
+struct Local {
+    name: Token,
+    // None until the initializer has been compiled.
+    depth: Option<usize>,
+    // Captured locals are moved off the stack when they go out of scope.
+    is_captured: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Upvalue {
+    // Slot in the enclosing function's locals if `is_local`, otherwise an
+    // index into the enclosing function's upvalues.
+    index: u8,
+    is_local: bool,
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+// How top-level code is compiled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompileMode {
+    Script,
+    // For code typed into the REPL: if the input ends with an expression
+    // statement outside any block, if or loop, its value is printed, and its
+    // semicolon may be left off.
+    Repl,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// Per-function compilation state. Compiling a nested function pushes a new
+// Compiler whose `enclosing` holds the state of the surrounding function.
+struct Compiler {
+    enclosing: Option<Box<Compiler>>,
+    chunk: Rc<RefCell<Chunk>>,
+    function_type: FunctionType,
+    name: Option<ObjRef>,
+    arity: u8,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // How many functions enclose this one; zero for the script.
+    function_depth: usize,
+}
+
+impl Compiler {
+    fn new(function_type: FunctionType, name: Option<ObjRef>, source: Rc<str>) -> Self {
+        let mut locals = Vec::with_capacity(LOCALS_MAX);
+        // Slot zero holds the function being called, or the receiver for
+        // methods, where it is reachable as `this`.
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: Token {
+                token_type: TokenType::Identifier,
+                source: slot_zero.to_owned(),
+                line: 0,
+                column: 0,
+                offset: 0,
+                length: 0,
+            },
+            depth: Some(0),
+            is_captured: false,
+        });
+        Self {
+            enclosing: None,
+            chunk: Rc::new(RefCell::new(Chunk {
+                source: Some(source),
+                ..Chunk::new()
+            })),
+            function_type,
+            name,
+            arity: 0,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            function_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<u8>, (ErrorCode, &'static str)> {
+        let found = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.source == name.source);
+        match found {
+            Some((_, local)) if local.depth.is_none() => {
+                Err((
+                    ErrorCode::UninitializedVariable,
+                    "Can't read local variable in its own initializer.",
+                ))
+            }
+            Some((slot, _)) => Ok(Some(slot as u8)),
+            None => Ok(None),
+        }
+    }
+
+    // Resolves `name` as a variable captured from an enclosing function,
+    // threading the capture through every function in between.
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<u8>, (ErrorCode, &'static str)> {
+        let Some(enclosing) = self.enclosing.as_deref_mut() else {
+            return Ok(None);
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local as usize].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn mark_roots(&self, heap: &mut Heap) {
+        let mut compiler = Some(self);
+        while let Some(current) = compiler {
+            for constant in &current.chunk.borrow().constants {
+                heap.mark_value(*constant);
+            }
+            current.name.trace(heap);
+            compiler = current.enclosing.as_deref();
+        }
+    }
+
+    fn add_upvalue(&mut self, index: u8, is_local: bool) -> Result<u8, (ErrorCode, &'static str)> {
+        let existing = self
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+        if let Some(existing) = existing {
+            return Ok(existing as u8);
+        }
+
+        if self.upvalues.len() == UPVALUES_MAX {
+            return Err((
+                ErrorCode::TooManyUpvalues,
+                "Too many closure variables in function.",
+            ));
+        }
+        self.upvalues.push(Upvalue { index, is_local });
+        Ok((self.upvalues.len() - 1) as u8)
+    }
 }
 
 #[derive(PartialEq, PartialOrd)]
@@ -30,6 +202,7 @@ enum Precedence {
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
 }
@@ -45,94 +218,744 @@ impl Precedence {
             Self::Comparison => Self::Term,
             Self::Term => Self::Factor,
             Self::Factor => Self::Unary,
-            Self::Unary => Self::Call,
+            Self::Unary => Self::Exponent,
+            Self::Exponent => Self::Call,
             Self::Call => Self::Primary,
             Self::Primary => Self::Primary,
         }
     }
 }
 
-struct ParseRule {
-    pub prefix: Option<fn(&mut Parser)>,
-    pub infix: Option<fn(&mut Parser)>,
+struct ParseRule<'vm> {
+    pub prefix: Option<fn(&mut Parser<'vm>, bool)>,
+    pub infix: Option<fn(&mut Parser<'vm>, bool)>,
     pub precedence: Precedence,
 }
 
 // impl<'a, '> ParseRule<'parser> {}
 
-impl Parser {
-    pub fn init(scanner: Scanner, chunk: Rc<RefCell<Chunk>>) -> Self {
+impl<'vm> Parser<'vm> {
+    pub fn init(scanner: Scanner, source: Rc<str>, mode: CompileMode, vm: &'vm mut VM) -> Self {
         Self {
+            vm,
             scanner: Rc::new(RefCell::new(scanner)),
-            chunk,
             current: Token {
                 token_type: TokenType::EOF,
                 source: "".to_owned(),
                 line: u32::MAX,
+                column: 0,
+                offset: 0,
+                length: 0,
             },
             previous: Token {
                 token_type: TokenType::EOF,
                 source: "".to_owned(),
                 line: u32::MAX,
+                column: 0,
+                offset: 0,
+                length: 0,
             },
-            had_error: false,
+            compiler: Compiler::new(FunctionType::Script, None, source.clone()),
+            class_compilers: vec![],
+            source,
+            expression_start: Span {
+                line: 0,
+                column: 0,
+                offset: 0,
+                length: 0,
+            },
+            expression_code_start: 0,
+            diagnostics: vec![],
             panic_mode: false,
+            mode,
         }
     }
     pub fn advance(&mut self) {
         self.previous = self.current.clone();
-        println!("{:?}", self.previous);
         loop {
             // let token = self.scanner.scan_token();
             // let token = self.scanner.borrow_mut().scan_token();
-            let scanner_ref = self.scanner.clone();
-            let mut scanner = RefCell::borrow_mut(&scanner_ref);
-
-            let token = scanner.scan_token();
-            match token.token_type {
+            let token = self.scanner.borrow_mut().scan_token();
+            if let Some(tracer) = self.vm.tracer() {
+                tracer.token(&token);
+            }
+            self.current = token;
+            match self.current.token_type {
                 TokenType::Error => {
-                    let lexeme = self.current.source.clone();
-                    self.error_at_current(&lexeme);
-                }
-                _ => {
-                    self.current = token;
-                    break;
+                    let message = self.current.source.clone();
+                    self.error_at_current(ErrorCode::InvalidToken, &message);
                 }
+                _ => break,
             }
         }
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.current.clone(), message);
+    fn error_at_current(&mut self, code: ErrorCode, message: &str) {
+        self.error_at(self.current.clone(), code, message);
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(self.previous.clone(), message);
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        self.error_at(self.previous.clone(), code, message);
     }
 
-    // FIXME: pass error details up in result type
-    fn error_at(&mut self, token: Token, message: &str) {
+    fn error_at(&mut self, token: Token, code: ErrorCode, message: &str) {
         if self.panic_mode {
             return;
         };
         self.panic_mode = true;
-        let mut stderr = std::io::stderr();
-        let token = token;
-        write!(stderr, "[line {}] Error", token.line).unwrap();
-        match token.token_type {
-            TokenType::EOF => write!(stderr, " at end").unwrap(),
-            TokenType::Error => (),
-            _ => write!(stderr, " at {}", token.source).unwrap(),
-        }
-        writeln!(stderr, ": {}.", message).unwrap();
-        self.had_error = true;
+        let error_token = match token.token_type {
+            TokenType::EOF => ErrorToken::End,
+            TokenType::Error => ErrorToken::Invalid,
+            _ => ErrorToken::Lexeme(token.source),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code,
+            message: message.to_owned(),
+            token: error_token,
+            line: token.line,
+            column: token.column,
+            span: token.offset..token.offset + token.length,
+        });
     }
 
     fn consume(&mut self, token_type: TokenType, message: &str) {
         if self.current.token_type == token_type {
             self.advance()
         } else {
-            self.error_at_current(message)
+            self.error_at_current(ErrorCode::UnexpectedToken, message)
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_op_at(op, self.span());
+    }
+
+    fn emit_op_at(&mut self, op: OpCode, span: Span) {
+        self.current_chunk().borrow_mut().add_code_op(op, span);
+    }
+
+    // The span of the token just consumed.
+    fn span(&self) -> Span {
+        Span {
+            line: self.previous.line,
+            column: self.previous.column,
+            offset: self.previous.offset,
+            length: self.previous.length,
+        }
+    }
+
+    // `top_level` is set only for declarations read directly by `compile`'s
+    // loop, not ones nested in blocks or in the body of an if or loop.
+    fn declaration(&mut self, top_level: bool) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement(top_level);
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_op_with_constant(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.class_compilers.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+            if class_name.source == self.previous.source {
+                self.error(ErrorCode::SelfInheritance, "A class can't inherit from itself.");
+            }
+
+            // The superclass lives in a local named `super` for the class
+            // body, so each method can capture it as an upvalue.
+            self.begin_scope();
+            self.add_local(self.synthetic_token("super"));
+            self.define_variable(ConstantIdx(0));
+
+            self.named_variable(&class_name, false);
+            self.emit_op(OpCode::Inherit);
+            self.class_compilers.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack so methods can be attached to it.
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::Pop);
+
+        if self.class_compilers.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn synthetic_token(&self, source: &str) -> Token {
+        Token {
+            token_type: TokenType::Identifier,
+            source: source.to_owned(),
+            line: self.previous.line,
+            column: self.previous.column,
+            offset: self.previous.offset,
+            length: self.previous.length,
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.source == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_op_with_constant(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so its name is usable before the
+        // body is compiled.
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.intern(&self.previous.source.clone());
+        let function_depth = self.compiler.function_depth + 1;
+        // Only the first function past the limit is reported, not every one
+        // nested inside it.
+        if function_depth == FUNCTION_DEPTH_MAX + 1 {
+            self.error(ErrorCode::TooManyNestedFunctions, "Too many nested functions.");
+        }
+        let enclosing = std::mem::replace(
+            &mut self.compiler,
+            Compiler::new(function_type, Some(name), self.source.clone()),
+        );
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        self.compiler.function_depth = function_depth;
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.compiler.arity == u8::MAX {
+                    self.error_at_current(ErrorCode::TooManyParameters, "Can't have more than 255 parameters.");
+                } else {
+                    self.compiler.arity += 1;
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.alloc(HeapValue::Function(function));
+        let constant = self.make_constant(Value::Object(function));
+        self.emit_op_with_constant(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    // Finishes the function currently being compiled and restores the
+    // enclosing compiler, if any.
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+
+        let compiler = match self.compiler.enclosing.take() {
+            Some(enclosing) => std::mem::replace(&mut self.compiler, *enclosing),
+            None => std::mem::replace(
+                &mut self.compiler,
+                Compiler::new(FunctionType::Script, None, self.source.clone()),
+            ),
+        };
+        let chunk = compiler.chunk.replace(Chunk::new());
+        if self.diagnostics.is_empty() {
+            if let Some(tracer) = self.vm.tracer() {
+                let name = compiler.name.as_ref();
+                let name = name.map_or("<script>", |name| name.as_string().as_str());
+                tracer.code(name, &chunk);
+            }
+        }
+        let function = ObjFunction {
+            arity: compiler.arity,
+            upvalue_count: compiler.upvalues.len() as u8,
+            chunk,
+            name: compiler.name,
+        };
+        (function, compiler.upvalues)
+    }
+
+    fn emit_return(&mut self) {
+        // Initializers implicitly return the instance.
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_op_with_byte(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> ConstantIdx {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return ConstantIdx(0);
+        }
+
+        let name = self.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.clone();
+        let scope_depth = self.compiler.scope_depth;
+        let shadows = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name.source == name.source);
+        if shadows {
+            self.error(ErrorCode::DuplicateVariable, "Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.compiler.locals.len() == LOCALS_MAX {
+            self.error(ErrorCode::TooManyLocals, "Too many local variables in function.");
+            return;
+        }
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+        self.compiler
+            .resolve_local(name)
+            .unwrap_or_else(|(code, message)| {
+                self.error(code, message);
+                None
+            })
+    }
+
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<u8> {
+        self.compiler
+            .resolve_upvalue(name)
+            .unwrap_or_else(|(code, message)| {
+                self.error(code, message);
+                None
+            })
+    }
+
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.compiler.scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    // Variable names are stored in the constant table and referenced by a
+    // single operand byte.
+    fn identifier_constant(&mut self, name: &Token) -> ConstantIdx {
+        let name = self.intern(&name.source);
+        self.make_constant(Value::Object(name))
+    }
+
+    // Adds a constant that will be referenced by a single operand byte.
+    fn make_constant(&mut self, value: Value) -> ConstantIdx {
+        let constant_idx = self.current_chunk().borrow_mut().add_constant(value);
+        if constant_idx.0 > 255 {
+            self.error(ErrorCode::TooManyConstants, "Too many constants in one chunk.");
+            return ConstantIdx(0);
+        }
+        constant_idx
+    }
+
+    fn define_variable(&mut self, global: ConstantIdx) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_op_with_constant(OpCode::DefineGlobal, global);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        self.emit_byte_at(byte, self.span());
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.current_chunk().borrow_mut().add_code_byte(byte, span);
+    }
+
+    fn emit_op_with_byte(&mut self, op: OpCode, byte: u8) {
+        self.emit_op_with_byte_at(op, byte, self.span());
+    }
+
+    fn emit_op_with_byte_at(&mut self, op: OpCode, byte: u8, span: Span) {
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        chunk.add_code_op(op, span);
+        chunk.add_code_byte(byte, span);
+    }
+
+    fn emit_op_with_constant(&mut self, op: OpCode, constant: ConstantIdx) {
+        self.emit_op_with_constant_at(op, constant, self.span());
+    }
+
+    fn emit_op_with_constant_at(&mut self, op: OpCode, constant: ConstantIdx, span: Span) {
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        chunk.add_code_op(op, span);
+        chunk.add_code_constant(constant, span);
+    }
+
+    fn statement(&mut self, top_level: bool) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(top_level);
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.declaration(false);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth.is_none_or(|depth| depth <= self.compiler.scope_depth) {
+                break;
+            }
+            if local.is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement(false);
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
+
+        if self.match_token(TokenType::Else) {
+            self.statement(false);
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error(ErrorCode::InvalidReturn, "Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error(ErrorCode::InvalidReturn, "Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(OpCode::Return);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().borrow().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.statement(false);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement(false);
+        }
+
+        let mut loop_start = self.current_chunk().borrow().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        // The increment clause runs after the body, so jump over it now and
+        // loop back to it from the end of the body.
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.current_chunk().borrow().code.len();
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement(false);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(OpCode::Pop);
+        }
+        self.end_scope();
+    }
+
+    // Emits a jump with a placeholder operand and returns the operand's
+    // offset so it can be patched once the target is known.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        let span = self.span();
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        chunk.add_code_op(op, span);
+        chunk.add_code_byte(0xff, span);
+        chunk.add_code_byte(0xff, span);
+        chunk.code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        // -2 to adjust for the jump operand itself.
+        let jump = chunk.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            drop(chunk);
+            self.error(ErrorCode::JumpTooLarge, "Too much code to jump over.");
+            return;
+        }
+        let bytes = (jump as u16).to_be_bytes();
+        chunk.code[offset] = bytes[0];
+        chunk.code[offset + 1] = bytes[1];
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        let span = self.span();
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        chunk.add_code_op(OpCode::Loop, span);
+
+        // +2 to skip over the Loop operand.
+        let offset = chunk.code.len() - loop_start + 2;
+        let offset = if offset > u16::MAX as usize {
+            drop(chunk);
+            self.error(ErrorCode::JumpTooLarge, "Loop body too large.");
+            chunk = RefCell::borrow_mut(&chunk_ref);
+            0
+        } else {
+            offset as u16
+        };
+        let bytes = offset.to_be_bytes();
+        chunk.add_code_byte(bytes[0], span);
+        chunk.add_code_byte(bytes[1], span);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_op(OpCode::Print);
+    }
+
+    fn expression_statement(&mut self, top_level: bool) {
+        self.expression();
+        let echo = top_level && self.mode == CompileMode::Repl;
+        if echo && self.check(TokenType::EOF) {
+            self.emit_op(OpCode::Print);
+            return;
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        if echo && self.check(TokenType::EOF) {
+            self.emit_op(OpCode::Print);
+        } else {
+            self.emit_op(OpCode::Pop);
+        }
+    }
+
+    // Skips tokens until a likely statement boundary so that one syntax error
+    // doesn't cascade into a wall of follow-on errors.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::EOF {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current.token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        if let Some(slot) = self.resolve_local(name) {
+            if can_assign && self.match_token(TokenType::Equal) {
+                self.expression();
+                self.emit_op_with_byte(OpCode::SetLocal, slot);
+            } else {
+                self.emit_op_with_byte(OpCode::GetLocal, slot);
+            }
+            return;
+        }
+
+        if let Some(slot) = self.resolve_upvalue(name) {
+            if can_assign && self.match_token(TokenType::Equal) {
+                self.expression();
+                self.emit_op_with_byte(OpCode::SetUpvalue, slot);
+            } else {
+                self.emit_op_with_byte(OpCode::GetUpvalue, slot);
+            }
+            return;
+        }
+
+        let arg = self.identifier_constant(name);
+        // Undefined variable errors point at the name even for assignments.
+        let span = Span {
+            line: name.line,
+            column: name.column,
+            offset: name.offset,
+            length: name.length,
+        };
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_op_with_constant_at(OpCode::SetGlobal, arg, span);
+        } else {
+            self.emit_op_with_constant_at(OpCode::GetGlobal, arg, span);
         }
     }
 
@@ -140,148 +963,317 @@ impl Parser {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression");
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn number(&mut self) {
+    fn number(&mut self, _can_assign: bool) {
         // let number = f64::From(self.previous.source);
-        println!("NUMBERRRR!");
-        println!("{}", self.previous.source);
         match f64::from_str(self.previous.source.as_str()) {
             Ok(number) => self.emit_constant(Value::Double(number)),
             // TODO: use InterprerError type?
-            Err(_) => self.error("Failed to parse number."),
+            Err(_) => self.error(ErrorCode::InvalidNumber, "Failed to parse number."),
         }
     }
 
-    fn unary(&mut self) {
-        let prev_token = self.previous.clone();
-        let op_type = prev_token.token_type;
+    fn unary(&mut self, _can_assign: bool) {
+        let op_type = self.previous.token_type;
+        let start = self.span();
         self.parse_precedence(Precedence::Unary);
+        let span = start.to(self.span());
         let chunk_ref = self.current_chunk();
         let mut chunk = RefCell::borrow_mut(&chunk_ref);
         match op_type {
-            TokenType::Minus => {
-                let line = prev_token.line;
-                chunk.add_code_op(OpCode::Negate, line)
-            }
-            TokenType::Bang => {
-                let line = prev_token.line;
-                chunk.add_code_op(OpCode::Not, line);
-            }
+            TokenType::Minus => chunk.add_code_op(OpCode::Negate, span),
+            TokenType::Bang => chunk.add_code_op(OpCode::Not, span),
             _ => panic!("Unexpected token type for unary operator."),
         }
     }
 
-    fn binary(&mut self) {
-        let prev_token = self.previous.clone();
-        let op_type = prev_token.token_type;
+    fn binary(&mut self, _can_assign: bool) {
+        let start = self.expression_start;
+        let left_start = self.expression_code_start;
+        let right_start = self.current_chunk().borrow().code.len();
+        let op_type = self.previous.token_type;
         let parse_rule = self.get_rule(op_type);
-        self.parse_precedence(parse_rule.precedence.next());
+        if op_type == TokenType::StarStar {
+            // Right-associative: `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+            self.parse_precedence(Precedence::Exponent);
+        } else {
+            self.parse_precedence(parse_rule.precedence.next());
+        }
+
+        // Errors cover both operands, e.g. all of `a + b`.
+        let span = start.to(self.span());
+        let arithmetic = match op_type {
+            TokenType::Plus => Some(OpCode::Add),
+            TokenType::Minus => Some(OpCode::Subtract),
+            TokenType::Star => Some(OpCode::Multiply),
+            TokenType::Slash => Some(OpCode::Divide),
+            TokenType::Percent => Some(OpCode::Modulo),
+            TokenType::StarStar => Some(OpCode::Power),
+            TokenType::TildeSlash => Some(OpCode::FloorDivide),
+            _ => None,
+        };
+        if let Some(opcode) = arithmetic {
+            if !self.fold_constants(opcode, left_start, right_start, span) {
+                self.emit_op_at(opcode, span);
+            }
+            return;
+        }
 
         let chunk_ref = self.current_chunk();
         let mut chunk = RefCell::borrow_mut(&chunk_ref);
-        let line = prev_token.line;
         match op_type {
-            TokenType::Plus => chunk.add_code_op(OpCode::Add, line),
-            TokenType::Minus => chunk.add_code_op(OpCode::Subtract, line),
-            TokenType::Star => chunk.add_code_op(OpCode::Multiply, line),
-            TokenType::Slash => chunk.add_code_op(OpCode::Divide, line),
-            TokenType::EqualEqual => chunk.add_code_op(OpCode::Equal, line),
+            TokenType::EqualEqual => chunk.add_code_op(OpCode::Equal, span),
             TokenType::BangEqual => {
-                chunk.add_code_op(OpCode::Equal, line);
-                chunk.add_code_op(OpCode::Not, line)
+                chunk.add_code_op(OpCode::Equal, span);
+                chunk.add_code_op(OpCode::Not, span)
             }
-            TokenType::Less => chunk.add_code_op(OpCode::Less, line),
+            TokenType::Less => chunk.add_code_op(OpCode::Less, span),
             TokenType::LessEqual => {
-                chunk.add_code_op(OpCode::Greater, line);
-                chunk.add_code_op(OpCode::Not, line)
+                chunk.add_code_op(OpCode::Greater, span);
+                chunk.add_code_op(OpCode::Not, span)
             }
-            TokenType::Greater => chunk.add_code_op(OpCode::Greater, line),
+            TokenType::Greater => chunk.add_code_op(OpCode::Greater, span),
             TokenType::GreaterEqual => {
-                chunk.add_code_op(OpCode::Less, line);
-                chunk.add_code_op(OpCode::Not, line)
+                chunk.add_code_op(OpCode::Less, span);
+                chunk.add_code_op(OpCode::Not, span)
             }
             _ => panic!("Unexpected token type for binary operator."),
         }
     }
 
-    fn literal(&mut self) {
-        let token = &self.previous;
+    // Replaces an arithmetic operation on two number constants with its
+    // result, e.g. `60 * 60` with `3600`, returning whether it did. The
+    // operands' code runs from `left_start` to `right_start` and from there
+    // to the end of the chunk.
+    fn fold_constants(&mut self, opcode: OpCode, left_start: usize, right_start: usize, span: Span) -> bool {
+        let chunk_ref = self.current_chunk();
+        let mut chunk = RefCell::borrow_mut(&chunk_ref);
+        let number = |start: usize, end: usize| {
+            if end - start != 2 || chunk.code[start] != OpCode::Constant as u8 {
+                return None;
+            }
+            let constant = chunk.code[start + 1] as usize;
+            chunk.constants[constant].as_number().map(|number| (constant, number))
+        };
+        let (Some((left, a)), Some((right, b))) = (
+            number(left_start, right_start),
+            number(right_start, chunk.code.len()),
+        ) else {
+            return false;
+        };
+        let Some(result) = opcode.arithmetic(a, b) else {
+            return false;
+        };
+
+        chunk.truncate(left_start);
+        // The operands' constants are usually the last two added, and
+        // nothing else refers to them.
+        if left + 1 == right && right + 1 == chunk.constants.len() {
+            chunk.constants.truncate(left);
+        }
+        drop(chunk);
+        self.emit_constant_at(Value::Double(result), span);
+        true
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let start = self.expression_start;
+        let arg_count = self.argument_list();
+        self.emit_op_with_byte_at(OpCode::Call, arg_count, start.to(self.span()));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: u8 = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX {
+                    self.error(ErrorCode::TooManyArguments, "Can't have more than 255 arguments.");
+                } else {
+                    arg_count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        let start = self.expression_start;
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+        let span = start.to(self.span());
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_op_with_constant_at(OpCode::SetProperty, name, span);
+        } else if self.match_token(TokenType::LeftParen) {
+            // Calling a method straight off an access skips creating a
+            // bound method.
+            let arg_count = self.argument_list();
+            let span = start.to(self.span());
+            self.emit_op_with_constant_at(OpCode::Invoke, name, span);
+            self.emit_byte_at(arg_count, span);
+        } else {
+            self.emit_op_with_constant_at(OpCode::GetProperty, name, span);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compilers.is_empty() {
+            self.error(ErrorCode::InvalidThis, "Can't use 'this' outside of a class.");
+            return;
+        }
+        self.variable(false);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.class_compilers.last() {
+            None => self.error(ErrorCode::InvalidSuper, "Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error(ErrorCode::InvalidSuper, "Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => (),
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        self.named_variable(&self.synthetic_token("this"), false);
+        if self.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&self.synthetic_token("super"), false);
+            self.emit_op_with_constant(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&self.synthetic_token("super"), false);
+            self.emit_op_with_constant(OpCode::GetSuper, name);
+        }
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        let span = self.span();
         let chunk_ref = self.current_chunk();
         let mut chunk = RefCell::borrow_mut(&chunk_ref);
-        let line = token.line;
-        match token.token_type {
-            TokenType::True => chunk.add_code_op(OpCode::True, line),
-            TokenType::False => chunk.add_code_op(OpCode::False, line),
-            TokenType::Nil => chunk.add_code_op(OpCode::Nil, line),
+        match self.previous.token_type {
+            TokenType::True => chunk.add_code_op(OpCode::True, span),
+            TokenType::False => chunk.add_code_op(OpCode::False, span),
+            TokenType::Nil => chunk.add_code_op(OpCode::Nil, span),
             _ => panic!("Unexpected token type for literal expression."),
         }
     }
 
-    fn string(&mut self) {
-        let token = &self.previous;
-        self.emit_constant(Value::Object(Obj {
-            value: HeapValue::String(BoxedObjString::of_ref(&token.source)),
-            next: None,
-        }));
+    fn string(&mut self, _can_assign: bool) {
+        let string = self.intern(&self.previous.source.clone());
+        self.emit_constant(Value::Object(string));
     }
 
     fn emit_constant(&mut self, value: Value) {
-        println!("emitting constatns.....");
-        let line = self.previous.line;
+        self.emit_constant_at(value, self.span());
+    }
+
+    fn emit_constant_at(&mut self, value: Value, span: Span) {
         let chunk_ref = self.current_chunk();
-        println!("got chunk ref..");
         let mut chunk = RefCell::borrow_mut(&chunk_ref);
-        println!("borrowed chunk...");
 
         let constant_idx = chunk.add_constant(value);
         if constant_idx.0 > 16777216 {
             // FIXME: error message
-            self.error("Too many constants in one chunk.");
+            self.error(ErrorCode::TooManyConstants, "Too many constants in one chunk.");
         } else if constant_idx.0 > 255 {
-            chunk.add_code_op(OpCode::ConstantLong, line);
-            chunk.add_code_constant_long(constant_idx, line);
+            chunk.add_code_op(OpCode::ConstantLong, span);
+            chunk.add_code_constant_long(constant_idx, span);
         } else {
-            chunk.add_code_op(OpCode::Constant, line);
-            chunk.add_code_constant(constant_idx, line);
+            chunk.add_code_op(OpCode::Constant, span);
+            chunk.add_code_constant(constant_idx, span);
         }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
+        let start = self.span();
+        let code_start = self.current_chunk().borrow().code.len();
         let token_type = self.previous.token_type;
         let prefix_rule = self.get_rule(token_type).prefix;
-        println!("{:?}", token_type);
+        let can_assign = precedence <= Precedence::Assignment;
         match prefix_rule {
-            Some(prefix_rule) => prefix_rule(self),
+            Some(prefix_rule) => prefix_rule(self, can_assign),
             None => {
-                self.error("Expect expression.");
+                self.error(ErrorCode::UnexpectedToken, "Expect expression.");
                 return;
             }
         }
         while precedence <= self.get_rule(self.current.token_type).precedence {
             self.advance();
+            self.expression_start = start;
+            self.expression_code_start = code_start;
             let infix_rule = self.get_rule(self.previous.token_type).infix;
-            infix_rule.unwrap()(self);
+            infix_rule.unwrap()(self, can_assign);
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error(ErrorCode::InvalidAssignmentTarget, "Invalid assignment target.");
         }
     }
 
+    // Functions that are still being compiled aren't reachable from the VM's
+    // roots yet, so their constants are marked here if allocating triggers a
+    // collection.
+    fn alloc(&mut self, value: HeapValue) -> ObjRef {
+        let compiler = &self.compiler;
+        self.vm
+            .alloc_with_roots(value, |heap| compiler.mark_roots(heap))
+    }
+
+    fn intern(&mut self, string: &str) -> ObjRef {
+        let compiler = &self.compiler;
+        self.vm
+            .intern_with_roots(string, |heap| compiler.mark_roots(heap))
+    }
+
     // TODO: investigate refcell<chunk>. what is going on here. is this bad? how is this compiled?
     fn current_chunk(&self) -> Rc<RefCell<Chunk>> {
-        self.chunk.clone()
+        self.compiler.chunk.clone()
     }
 
     // Vaughan-Pratt precendence rule lookup.
     // TODO: revisit get ParseRule match -- Can we make this more performant? Does the compiler turn this into a LUT?
-    fn get_rule(&self, op_type: TokenType) -> ParseRule {
+    fn get_rule(&self, op_type: TokenType) -> ParseRule<'vm> {
         match op_type {
             TokenType::LeftParen => ParseRule {
                 prefix: Some(Self::grouping),
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Self::call),
+                precedence: Precedence::Call,
             },
             TokenType::RightParen => ParseRule {
                 prefix: None,
@@ -305,8 +1297,8 @@ impl Parser {
             },
             TokenType::Dot => ParseRule {
                 prefix: None,
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Self::dot),
+                precedence: Precedence::Call,
             },
             TokenType::Minus => ParseRule {
                 prefix: Some(Self::unary),
@@ -333,6 +1325,21 @@ impl Parser {
                 infix: Some(Self::binary),
                 precedence: Precedence::Factor,
             },
+            TokenType::Percent => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::Factor,
+            },
+            TokenType::TildeSlash => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::Factor,
+            },
+            TokenType::StarStar => ParseRule {
+                prefix: None,
+                infix: Some(Self::binary),
+                precedence: Precedence::Exponent,
+            },
             TokenType::Bang => ParseRule {
                 prefix: Some(Self::unary),
                 infix: None,
@@ -374,7 +1381,7 @@ impl Parser {
                 precedence: Precedence::Comparison,
             },
             TokenType::Identifier => ParseRule {
-                prefix: None,
+                prefix: Some(Self::variable),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -390,8 +1397,8 @@ impl Parser {
             },
             TokenType::And => ParseRule {
                 prefix: None,
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Self::and),
+                precedence: Precedence::And,
             },
             TokenType::Class => ParseRule {
                 prefix: None,
@@ -430,8 +1437,8 @@ impl Parser {
             },
             TokenType::Or => ParseRule {
                 prefix: None,
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Self::or),
+                precedence: Precedence::Or,
             },
             TokenType::Print => ParseRule {
                 prefix: None,
@@ -444,12 +1451,12 @@ impl Parser {
                 precedence: Precedence::None,
             },
             TokenType::Super => ParseRule {
-                prefix: None,
+                prefix: Some(Self::super_),
                 infix: None,
                 precedence: Precedence::None,
             },
             TokenType::This => ParseRule {
-                prefix: None,
+                prefix: Some(Self::this),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -482,20 +1489,18 @@ impl Parser {
     }
 }
 
-pub fn compile(source: String, chunk: Rc<RefCell<Chunk>>) -> Result<(), InterpretError> {
+pub fn compile(source: String, mode: CompileMode, vm: &mut VM) -> Result<ObjRef, Vec<Diagnostic>> {
+    let shared_source: Rc<str> = Rc::from(source.as_str());
     let scanner = Scanner::init(source);
-    let mut parser = Parser::init(scanner, chunk.clone());
+    let mut parser = Parser::init(scanner, shared_source, mode, vm);
     parser.advance();
-    parser.expression();
-    parser.consume(TokenType::EOF, "Expect end of expression.");
-    let chunk_ref = chunk.clone();
-    let mut chunk = RefCell::borrow_mut(&chunk_ref);
-    chunk.add_code_op(OpCode::Return, 5);
-
-    println!("{}", chunk);
+    while !parser.match_token(TokenType::EOF) {
+        parser.declaration(true);
+    }
+    let (function, _) = parser.end_compiler();
 
-    match parser.had_error {
-        true => Err(InterpretError::CompileError),
-        false => Ok(()),
+    match parser.diagnostics.is_empty() {
+        true => Ok(parser.alloc(HeapValue::Function(function))),
+        false => Err(parser.diagnostics),
     }
 }
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Stable identifiers for each kind of compile error, so tools don't have to
+// match on message text.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorCode {
+    // Lexical and syntax errors.
+    InvalidToken,
+    UnexpectedToken,
+    InvalidAssignmentTarget,
+    InvalidNumber,
+    // Variable resolution.
+    DuplicateVariable,
+    UninitializedVariable,
+    // Statements used where they aren't allowed.
+    InvalidReturn,
+    InvalidThis,
+    InvalidSuper,
+    SelfInheritance,
+    // Limits of the bytecode format.
+    TooManyConstants,
+    TooManyLocals,
+    TooManyUpvalues,
+    TooManyParameters,
+    TooManyArguments,
+    JumpTooLarge,
+    TooManyNestedFunctions,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidToken => "E0001",
+            ErrorCode::UnexpectedToken => "E0002",
+            ErrorCode::InvalidAssignmentTarget => "E0003",
+            ErrorCode::InvalidNumber => "E0004",
+            ErrorCode::DuplicateVariable => "E0100",
+            ErrorCode::UninitializedVariable => "E0101",
+            ErrorCode::InvalidReturn => "E0200",
+            ErrorCode::InvalidThis => "E0201",
+            ErrorCode::InvalidSuper => "E0202",
+            ErrorCode::SelfInheritance => "E0203",
+            ErrorCode::TooManyConstants => "E0300",
+            ErrorCode::TooManyLocals => "E0301",
+            ErrorCode::TooManyUpvalues => "E0302",
+            ErrorCode::TooManyParameters => "E0303",
+            ErrorCode::TooManyArguments => "E0304",
+            ErrorCode::JumpTooLarge => "E0305",
+            ErrorCode::TooManyNestedFunctions => "E0306",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// The token a diagnostic was reported at.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorToken {
+    Lexeme(String),
+    End,
+    // The scanner couldn't make a token out of the input.
+    Invalid,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    pub token: ErrorToken,
+    pub line: u32,
+    // 1-based, counted in characters.
+    pub column: u32,
+    // Byte range of the token in the source.
+    pub span: Range<usize>,
+}
+
+// Renders the diagnostic the way clox reports compile errors, e.g.
+// `[line 1] Error at 'x': Expect ';' after value.`
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "[line {}] {}", self.line, severity)?;
+        match &self.token {
+            ErrorToken::Lexeme(lexeme) => write!(f, " at '{}'", lexeme)?,
+            ErrorToken::End => write!(f, " at end")?,
+            ErrorToken::Invalid => (),
+        }
+        write!(f, ": {}", self.message)
+    }
+}
@@ -1,5 +1,9 @@
 // pub mod compiler;
+#[allow(clippy::module_inception)]
 mod compiler;
 pub(super) use self::compiler::*;
+pub use self::compiler::CompileMode;
 mod scanner;
-pub(super) use self::scanner::*;
+pub use self::scanner::*;
+mod diagnostic;
+pub use self::diagnostic::*;
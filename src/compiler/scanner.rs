@@ -3,6 +3,12 @@ pub struct Scanner {
     current: usize,
     start: usize,
     line: u32,
+    // Byte offset of the first character on the current line.
+    line_start: usize,
+    // Line and column the token being scanned starts at, which differ from
+    // the current position for strings spanning several lines.
+    start_line: u32,
+    start_column: u32,
 }
 // impl DerefMut for Scanner {
 //     type Target = Scanner;
@@ -17,8 +23,15 @@ pub struct Token {
     // pub source: &'source str,
     pub source: String,
     pub line: u32,
+    // 1-based, counted in characters.
+    pub column: u32,
+    // Byte range of the lexeme in the source, including the quotes of
+    // string literals.
+    pub offset: usize,
+    pub length: usize,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TokenType {
     // Single character token
@@ -33,7 +46,10 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
     // One or two character tokens
+    TildeSlash,
+    StarStar,
     Bang,
     BangEqual,
     Equal,
@@ -98,29 +114,17 @@ impl Scanner {
             current: 0,
             start: 0,
             line: 1,
-        }
-    }
-
-    pub fn scan(&mut self) {
-        let mut line = u32::MAX;
-        loop {
-            let token = self.scan_token();
-            if token.line != line {
-                print!("{:03x} ", token.line);
-                line = token.line;
-            } else {
-                print!("   | ");
-            }
-            println!("{:?} {}", token.token_type, token.source);
-            if token.token_type == TokenType::EOF {
-                break;
-            }
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column();
         match self.current == self.source.len() {
             true => self.make_token(TokenType::EOF),
             false => match self.advance() {
@@ -133,8 +137,18 @@ impl Scanner {
                 ',' => self.make_token(TokenType::Comma),
                 '-' => self.make_token(TokenType::Minus),
                 '+' => self.make_token(TokenType::Plus),
+                '%' => self.make_token(TokenType::Percent),
                 '/' => self.make_token(TokenType::Slash),
-                '*' => self.make_token(TokenType::Star),
+                // Floor division is spelled `~/`, as `//` starts a comment.
+                '~' if self.peek_match('/') => self.make_token(TokenType::TildeSlash),
+                '*' => {
+                    let token_type = if self.peek_match('*') {
+                        TokenType::StarStar
+                    } else {
+                        TokenType::Star
+                    };
+                    self.make_token(token_type)
+                }
                 '!' => {
                     let token_type = if self.peek_match('=') {
                         TokenType::BangEqual
@@ -215,7 +229,10 @@ impl Scanner {
         Token {
             token_type,
             source: self.source[self.start..self.current].to_string(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -223,7 +240,10 @@ impl Scanner {
         Token {
             token_type: TokenType::String,
             source: self.source[self.start + 1..self.current - 1].to_string(),
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -231,15 +251,30 @@ impl Scanner {
         Token {
             token_type: TokenType::Error,
             source: message,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            offset: self.start,
+            length: self.current - self.start,
         }
     }
 
+    // Column of the current position. Counts UTF-8 lead bytes so multi-byte
+    // characters take up a single column.
+    fn column(&self) -> u32 {
+        let line = &self.source.as_bytes()[self.line_start..self.current];
+        line.iter().filter(|byte| **byte & 0xc0 != 0x80).count() as u32 + 1
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
                 '/' => {
-                    if self.peek_match('/') {
+                    if self.peek_next() == '/' {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
@@ -248,8 +283,8 @@ impl Scanner {
                     }
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
+                    self.new_line();
                 }
                 c if c.is_ascii_whitespace() => {
                     self.advance();
@@ -261,10 +296,9 @@ impl Scanner {
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
         if self.is_at_end() {
             self.error_token("Unterminated string literal.".to_owned())
@@ -289,7 +323,7 @@ impl Scanner {
     }
 
     fn identifier(&mut self) -> Token {
-        while self.peek().is_alphabetic() || self.peek() == '_' {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
             self.advance();
         }
 
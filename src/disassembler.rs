@@ -0,0 +1,184 @@
+use std::fmt::{self, Write};
+
+use crate::common::{Chunk, HeapValue, ObjFunction, ObjRef, Operand, Value};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisassemblyFormat {
+    // One `== name ==` listing per function, as printed by `--print-code`.
+    Text,
+    // A single JSON document, for tools.
+    Json,
+}
+
+pub(crate) fn disassemble(script: ObjRef, format: DisassemblyFormat) -> String {
+    let functions = functions(script);
+    let mut out = String::new();
+    match format {
+        DisassemblyFormat::Text => {
+            for (n, function) in functions.iter().enumerate() {
+                if n > 0 {
+                    out.push('\n');
+                }
+                let function = function.as_function();
+                writeln!(out, "== {} ==", function_name(function)).unwrap();
+                write!(out, "{}", function.chunk).unwrap();
+            }
+        }
+        DisassemblyFormat::Json => write_json(&mut out, &functions).unwrap(),
+    }
+    out
+}
+
+// The script followed by every function nested in it, depth first.
+fn functions(script: ObjRef) -> Vec<ObjRef> {
+    fn visit(function: ObjRef, functions: &mut Vec<ObjRef>) {
+        functions.push(function);
+        for constant in &function.as_function().chunk.constants {
+            if let Value::Object(obj) = constant {
+                if let HeapValue::Function(_) = obj.value {
+                    visit(*obj, functions);
+                }
+            }
+        }
+    }
+
+    let mut functions = vec![];
+    visit(script, &mut functions);
+    functions
+}
+
+fn function_name(function: &ObjFunction) -> String {
+    match function.name {
+        Some(name) => name.as_string().as_str().to_owned(),
+        None => "<script>".to_owned(),
+    }
+}
+
+// {"functions": [{"name", "arity", "upvalue_count", "constants", "code"}]}
+// where each instruction has an offset, line, opcode and its decoded
+// operands. The script's name is null.
+fn write_json(out: &mut String, functions: &[ObjRef]) -> fmt::Result {
+    write!(out, "{{\"functions\":[")?;
+    for (n, function) in functions.iter().enumerate() {
+        if n > 0 {
+            write!(out, ",")?;
+        }
+        let function = function.as_function();
+        write!(out, "{{\"name\":")?;
+        match function.name {
+            Some(name) => write_json_string(out, name.as_string().as_str())?,
+            None => write!(out, "null")?,
+        }
+        write!(
+            out,
+            ",\"arity\":{},\"upvalue_count\":{},\"constants\":[",
+            function.arity, function.upvalue_count
+        )?;
+        for (n, constant) in function.chunk.constants.iter().enumerate() {
+            if n > 0 {
+                write!(out, ",")?;
+            }
+            write_json_value(out, constant)?;
+        }
+        write!(out, "],\"code\":[")?;
+        write_json_code(out, &function.chunk)?;
+        write!(out, "]}}")?;
+    }
+    writeln!(out, "]}}")
+}
+
+fn write_json_code(out: &mut String, chunk: &Chunk) -> fmt::Result {
+    let labels = chunk.jump_targets();
+    // Decoded once up front, since looking up each span decodes from the
+    // start.
+    let runs: Vec<_> = chunk.debug_info.runs().collect();
+    for (n, instruction) in chunk.instructions().enumerate() {
+        if n > 0 {
+            write!(out, ",")?;
+        }
+        let run = runs.partition_point(|(start, _)| *start <= instruction.offset);
+        let span = runs[run - 1].1;
+        write!(
+            out,
+            "{{\"offset\":{},\"line\":{},\"column\":{},\"opcode\":\"{:?}\"",
+            instruction.offset, span.line, span.column, instruction.opcode
+        )?;
+        if let Ok(label) = labels.binary_search(&instruction.offset) {
+            write!(out, ",\"label\":\"L{}\"", label)?;
+        }
+        match &instruction.operand {
+            Operand::None => (),
+            Operand::Constant(idx) => write!(out, ",\"constant\":{}", idx)?,
+            Operand::Slot(slot) => write!(out, ",\"slot\":{}", slot)?,
+            Operand::ArgCount(count) => write!(out, ",\"arg_count\":{}", count)?,
+            Operand::Invoke {
+                constant,
+                arg_count,
+            } => write!(out, ",\"constant\":{},\"arg_count\":{}", constant, arg_count)?,
+            Operand::Closure { constant, upvalues } => {
+                write!(out, ",\"constant\":{},\"upvalues\":[", constant)?;
+                for (n, (is_local, index)) in upvalues.iter().enumerate() {
+                    if n > 0 {
+                        write!(out, ",")?;
+                    }
+                    write!(out, "{{\"local\":{},\"index\":{}}}", is_local, index)?;
+                }
+                write!(out, "]")?;
+            }
+            Operand::Jump(target) => {
+                let label = labels.binary_search(target).unwrap();
+                write!(out, ",\"target\":{},\"target_label\":\"L{}\"", target, label)?;
+            }
+        }
+        write!(out, "}}")?;
+    }
+    Ok(())
+}
+
+fn write_json_value(out: &mut String, value: &Value) -> fmt::Result {
+    match value {
+        Value::Nil => write!(out, "{{\"type\":\"nil\"}}"),
+        Value::Boolean(b) => write!(out, "{{\"type\":\"bool\",\"value\":{}}}", b),
+        // JSON has no infinities or NaN.
+        Value::Double(number) if !number.is_finite() => {
+            write!(out, "{{\"type\":\"number\",\"value\":")?;
+            write_json_string(out, &number.to_string())?;
+            write!(out, "}}")
+        }
+        Value::Double(number) => write!(out, "{{\"type\":\"number\",\"value\":{}}}", number),
+        Value::Object(obj) => match &obj.value {
+            HeapValue::String(string) => {
+                write!(out, "{{\"type\":\"string\",\"value\":")?;
+                write_json_string(out, string.as_str())?;
+                write!(out, "}}")
+            }
+            HeapValue::Function(function) => {
+                write!(out, "{{\"type\":\"function\",\"value\":")?;
+                write_json_string(out, &function_name(function))?;
+                write!(out, "}}")
+            }
+            other => {
+                write!(out, "{{\"type\":\"object\",\"value\":")?;
+                write_json_string(out, &other.to_string())?;
+                write!(out, "}}")
+            }
+        },
+    }
+}
+
+fn write_json_string(out: &mut String, string: &str) -> fmt::Result {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
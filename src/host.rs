@@ -0,0 +1,95 @@
+use std::fmt;
+
+use crate::common::{self, HeapValue};
+
+// A value passed between Lox and the program hosting it. Unlike the VM's
+// own values, which point into its garbage-collected heap, these own their
+// data, so the host can keep them for as long as it likes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Double(f64),
+    Boolean(bool),
+    String(String),
+    // Any other object: a function, class or instance. Those can't leave
+    // the VM, so this only holds how Lox prints it, e.g. `<fn add>`, and
+    // can't be passed back in.
+    Object(String),
+    Nil,
+}
+
+impl Value {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Double(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    // Copies a value out of the VM. `value` must still be reachable, e.g.
+    // on the stack.
+    pub(crate) fn from_vm(value: common::Value) -> Self {
+        match value {
+            common::Value::Double(val) => Value::Double(val),
+            common::Value::Boolean(val) => Value::Boolean(val),
+            common::Value::Nil => Value::Nil,
+            common::Value::Object(obj) => match &obj.value {
+                HeapValue::String(string) => Value::String(string.as_str().to_owned()),
+                other => Value::Object(other.to_string()),
+            },
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value::Double(val)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Self {
+        Value::Boolean(val)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(val: &str) -> Self {
+        Value::String(val.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(val: String) -> Self {
+        Value::String(val)
+    }
+}
+
+// Prints the value the way Lox's `print` does.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Double(val) => write!(f, "{}", val),
+            Value::Boolean(val) => write!(f, "{}", val),
+            Value::String(val) | Value::Object(val) => write!(f, "{}", val),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
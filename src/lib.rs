@@ -0,0 +1,19 @@
+mod common;
+mod compiler;
+mod disassembler;
+mod host;
+mod repl;
+mod serialize;
+mod tracer;
+mod verifier;
+mod vm;
+
+pub use common::{Chunk, Instruction, NativeFn, OpCode, Operand, RuntimeError, Span, StackFrame};
+pub use compiler::{CompileMode, Diagnostic, ErrorCode, ErrorToken, Severity, Token, TokenType};
+pub use disassembler::DisassemblyFormat;
+pub use host::Value;
+pub use repl::{is_incomplete, Repl};
+pub use serialize::{is_bytecode, LoadError};
+pub use tracer::{PrintTracer, Tracer};
+pub use verifier::VerifyError;
+pub use vm::{InterpretError, VM};
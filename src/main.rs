@@ -1,22 +1,179 @@
-pub mod common;
-pub mod compiler;
-pub mod vm;
+use std::{
+    env, fs,
+    io,
+    path::{Path, PathBuf},
+};
 
-use std::env;
+use rlox::{is_bytecode, CompileMode, DisassemblyFormat, InterpretError, PrintTracer, Repl, VM};
 
-use vm::{InterpretError, VM};
+// Command-line options that configure the VM.
+#[derive(Clone, Copy, Default)]
+struct VmOptions {
+    stress_gc: bool,
+    trace_tokens: bool,
+    print_code: bool,
+    trace_exec: bool,
+}
+
+impl VmOptions {
+    fn new_vm(self) -> VM {
+        let mut vm = VM::new();
+        vm.set_stress_gc(self.stress_gc);
+        if self.trace_tokens || self.print_code || self.trace_exec {
+            let mut tracer = PrintTracer::default();
+            tracer.trace_tokens = self.trace_tokens;
+            tracer.print_code = self.print_code;
+            tracer.trace_exec = self.trace_exec;
+            vm.set_tracer(Some(Box::new(tracer)));
+        }
+        vm
+    }
+}
 
 fn main() -> Result<(), InterpretError> {
-    let mut vm = VM::init();
-
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => vm.repl(),
-        2 => vm.run_file(&args[1]),
-        _ => {
-            println!("Usage: rlox [path]");
-            std::process::exit(64);
+    let mut options = VmOptions::default();
+    let mut paths = vec![];
+    let mut output = None;
+    let mut format = DisassemblyFormat::Text;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stress-gc" => options.stress_gc = true,
+            "--trace-tokens" => options.trace_tokens = true,
+            "--print-code" => options.print_code = true,
+            "--trace-exec" => options.trace_exec = true,
+            "--json" => format = DisassemblyFormat::Json,
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            flag if flag.starts_with('-') => usage(),
+            _ => paths.push(arg),
         }
     }
+
+    if paths.is_empty() && output.is_none() && format == DisassemblyFormat::Text {
+        repl(options);
+        return Ok(());
+    }
+    let mut vm = options.new_vm();
+    let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+    let json = format == DisassemblyFormat::Json;
+    match (paths.as_slice(), output) {
+        (["disasm", path], None) => disassemble_file(&mut vm, path, format),
+        _ if json => usage(),
+        (["compile", path], output) => compile_file(&mut vm, path, output),
+        (["run", path] | [path], None) => run_file(&mut vm, path),
+        _ => usage(),
+    }
     Ok(())
 }
+
+fn usage() -> ! {
+    println!("Usage: rlox [options] [path]");
+    println!("       rlox compile <path> [-o <output>]");
+    println!("       rlox run <path>");
+    println!("       rlox disasm [--json] <path>");
+    println!();
+    println!("Paths may be Lox source or bytecode written by `rlox compile`.");
+    println!();
+    println!("Options:");
+    println!("  --stress-gc     Collect garbage before every allocation");
+    println!("  --trace-tokens  Print each token as it is scanned");
+    println!("  --print-code    Print the bytecode of each compiled function");
+    println!("  --trace-exec    Print the stack and each instruction as it runs");
+    println!("  --json          Print `disasm` output as JSON");
+    println!("  -o <output>     Where `compile` writes bytecode (default: <path>.loxc)");
+    std::process::exit(64);
+}
+
+fn repl(options: VmOptions) {
+    let mut repl = Repl::new(move || options.new_vm());
+    // History goes to $RLOX_HISTORY, or ~/.rlox_history by default.
+    let history = env::var_os("RLOX_HISTORY")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".rlox_history")));
+    if let Some(history) = history {
+        if let Err(e) = repl.set_history_file(&history) {
+            eprintln!("Couldn't open history file {}: {}", history.display(), e);
+        }
+    }
+    repl.run(io::stdin().lock());
+}
+
+fn run_file(vm: &mut VM, path: &str) {
+    let contents = read_file(path);
+    let result = if is_bytecode(&contents) {
+        vm.interpret_bytecode(&contents)
+    } else {
+        match String::from_utf8(contents) {
+            Ok(source) => vm.interpret(&source),
+            Err(_) => {
+                eprintln!("File is neither UTF-8 source nor compiled bytecode.");
+                std::process::exit(65)
+            }
+        }
+    };
+    if let Err(error) = result {
+        vm.report(&error);
+        match error {
+            InterpretError::CompileError(_) | InterpretError::LoadError(_) => std::process::exit(65),
+            InterpretError::RuntimeError(_) => std::process::exit(70),
+        }
+    }
+}
+
+fn compile_file(vm: &mut VM, path: &str, output: Option<String>) {
+    let Ok(source) = String::from_utf8(read_file(path)) else {
+        eprintln!("Source file isn't valid UTF-8.");
+        std::process::exit(65)
+    };
+    let bytecode = match vm.compile_bytecode(&source) {
+        Ok(bytecode) => bytecode,
+        Err(diagnostics) => {
+            vm.report(&InterpretError::CompileError(diagnostics));
+            std::process::exit(65)
+        }
+    };
+    let output = output.unwrap_or_else(|| {
+        Path::new(path)
+            .with_extension("loxc")
+            .to_string_lossy()
+            .into_owned()
+    });
+    if let Err(e) = fs::write(&output, bytecode) {
+        eprintln!("Couldn't write {}: {}", output, e);
+        std::process::exit(74)
+    }
+}
+
+fn disassemble_file(vm: &mut VM, path: &str, format: DisassemblyFormat) {
+    let contents = read_file(path);
+    let listing = if is_bytecode(&contents) {
+        vm.disassemble_bytecode(&contents, format).unwrap_or_else(|error| {
+            vm.report(&InterpretError::LoadError(error));
+            std::process::exit(65)
+        })
+    } else {
+        let Ok(source) = String::from_utf8(contents) else {
+            eprintln!("File is neither UTF-8 source nor compiled bytecode.");
+            std::process::exit(65)
+        };
+        vm.disassemble(&source, CompileMode::Script, format).unwrap_or_else(|diagnostics| {
+            vm.report(&InterpretError::CompileError(diagnostics));
+            std::process::exit(65)
+        })
+    };
+    print!("{}", listing);
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            match e.kind() {
+                io::ErrorKind::NotFound => println!("File not found."),
+                io::ErrorKind::PermissionDenied => println!("Permission denied reading file."),
+                _ => println!("Unexpected error reading file. {:?}", e),
+            }
+            std::process::exit(74)
+        }
+    }
+}
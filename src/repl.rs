@@ -0,0 +1,182 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, Write},
+    path::Path,
+    time::Instant,
+};
+
+use crate::compiler::{CompileMode, Scanner, TokenType};
+use crate::disassembler::DisassemblyFormat;
+use crate::vm::{InterpretError, VM};
+
+const HELP: &str = "\
+Enter Lox code to run it. Globals are kept between entries, and the value
+of an expression entered on its own is printed.
+Input continues on the next line while braces, parentheses or a string
+are left open.
+
+Commands:
+  :disasm [code]  Show the bytecode for code, or for the last entry
+  :globals        List global variables and their values
+  :reset          Start over with a fresh VM
+  :load <path>    Run a file, keeping the globals it defines
+  :time <code>    Run code and report how long it took
+  :help           Show this message
+  :quit           Exit (as does end of input)";
+
+// An interactive session reading Lox code from the user, with one VM that
+// lives until the session ends or is reset. Prompts and command output go to
+// the VM's writers along with the program's.
+pub struct Repl {
+    new_vm: Box<dyn Fn() -> VM>,
+    vm: VM,
+    // The last code entered, for `:disasm` without arguments.
+    last_entry: Option<String>,
+    history: Option<File>,
+}
+
+impl Repl {
+    // `new_vm` creates the session's VM, and a new one on `:reset`.
+    pub fn new(new_vm: impl Fn() -> VM + 'static) -> Self {
+        let vm = new_vm();
+        Repl {
+            new_vm: Box::new(new_vm),
+            vm,
+            last_entry: None,
+            history: None,
+        }
+    }
+
+    // Appends every entry to the file at `path`.
+    pub fn set_history_file(&mut self, path: &Path) -> io::Result<()> {
+        self.history = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(())
+    }
+
+    // Reads entries until the input ends, the user quits or the output can't
+    // be written.
+    pub fn run(&mut self, input: impl BufRead) {
+        let _ = self.read_entries(input);
+        let _ = self.vm.stdout().flush();
+    }
+
+    fn read_entries(&mut self, input: impl BufRead) -> io::Result<()> {
+        let mut lines = input.lines();
+        let mut entry = String::new();
+        loop {
+            let stdout = self.vm.stdout();
+            write!(stdout, "{}", if entry.is_empty() { "> " } else { "... " })?;
+            stdout.flush()?;
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(error)) => return writeln!(self.vm.stderr(), "{}", error),
+                None => return writeln!(self.vm.stdout()),
+            };
+            if entry.is_empty() && line.trim().is_empty() {
+                continue;
+            }
+            if entry.is_empty() && line.starts_with(':') {
+                self.record(&line);
+                if !self.command(&line)? {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            entry.push_str(&line);
+            entry.push('\n');
+            if is_incomplete(&entry) {
+                continue;
+            }
+            let source = std::mem::take(&mut entry);
+            self.record(source.trim_end());
+            self.eval(&source, CompileMode::Repl);
+            self.last_entry = Some(source);
+        }
+    }
+
+    // Runs a meta-command, returning false if the session should end.
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match command {
+            ":disasm" => {
+                let source = match (argument, &self.last_entry) {
+                    ("", Some(last_entry)) => last_entry.clone(),
+                    ("", None) => {
+                        writeln!(self.vm.stderr(), "Nothing to disassemble yet.")?;
+                        return Ok(true);
+                    }
+                    (code, _) => code.to_owned(),
+                };
+                match self.vm.disassemble(&source, CompileMode::Repl, DisassemblyFormat::Text) {
+                    Ok(listing) => write!(self.vm.stdout(), "{}", listing)?,
+                    Err(diagnostics) => self.vm.report(&InterpretError::CompileError(diagnostics)),
+                }
+            }
+            ":globals" => {
+                for (name, value) in self.vm.globals() {
+                    writeln!(self.vm.stdout(), "{} = {}", name, value)?;
+                }
+            }
+            ":reset" => {
+                self.vm = (self.new_vm)();
+                self.last_entry = None;
+            }
+            ":load" if !argument.is_empty() => match fs::read_to_string(argument) {
+                Ok(source) => self.eval(&source, CompileMode::Script),
+                Err(error) => writeln!(self.vm.stderr(), "Couldn't read {}: {}", argument, error)?,
+            },
+            ":time" if !argument.is_empty() => {
+                let start = Instant::now();
+                self.eval(argument, CompileMode::Repl);
+                writeln!(self.vm.stdout(), "Took {:?}.", start.elapsed())?;
+            }
+            ":help" => writeln!(self.vm.stdout(), "{}", HELP)?,
+            ":quit" => return Ok(false),
+            ":load" => writeln!(self.vm.stderr(), "Usage: :load <path>")?,
+            ":time" => writeln!(self.vm.stderr(), "Usage: :time <code>")?,
+            _ => writeln!(self.vm.stderr(), "Unknown command '{}'. Try :help.", command)?,
+        }
+        Ok(true)
+    }
+
+    fn eval(&mut self, source: &str, mode: CompileMode) {
+        if let Err(error) = self.vm.interpret_with_mode(source, mode) {
+            self.vm.report(&error);
+        }
+    }
+
+    fn record(&mut self, entry: &str) {
+        if let Some(history) = &mut self.history {
+            // History is a convenience; don't interrupt the session over it.
+            let _ = writeln!(history, "{}", entry);
+        }
+    }
+}
+
+// Whether `source` stops partway through, with braces or parentheses left
+// open or an unterminated string, so more input should be read before
+// compiling it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::init(source.to_owned());
+    let mut braces = 0;
+    let mut parens = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.token_type {
+            TokenType::LeftBrace => braces += 1,
+            TokenType::RightBrace => braces -= 1,
+            TokenType::LeftParen => parens += 1,
+            TokenType::RightParen => parens -= 1,
+            // Strings are the only tokens that can run to the end of the
+            // input unterminated.
+            TokenType::Error if source[token.offset..].starts_with('"') => return true,
+            TokenType::EOF => return braces > 0 || parens > 0,
+            _ => (),
+        }
+    }
+}
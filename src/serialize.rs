@@ -0,0 +1,321 @@
+// The `.loxc` precompiled script format. All integers are little-endian.
+//
+//   magic        b"LOXC"
+//   version      u16
+//   function     the top-level script, see below
+//   checksum     u32, FNV-1a of every preceding byte
+//
+// A function is written as
+//
+//   name         u8 flag (0 for the script), then a string if present
+//   arity        u8
+//   upvalues     u8
+//   code         u32 length, then the bytes
+//   constants    u32 count, then one tagged constant each
+//   debug info   u32 run count, then for each run its code offset and the
+//                span's line, column, byte offset and length as u32s
+//
+// Nested functions appear inline as constants, at most FUNCTION_DEPTH_MAX
+// deep. Source text isn't included, so runtime errors from loaded scripts
+// have no snippet. Every function is verified as it's loaded.
+
+use std::fmt;
+
+use crate::common::{
+    Chunk, DebugInfo, Heap, HeapValue, ObjFunction, ObjRef, Span, Value, FUNCTION_DEPTH_MAX,
+};
+use crate::verifier::{self, VerifyError};
+use crate::vm::VM;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Truncated,
+    Malformed(&'static str),
+    // The file is well-formed but its bytecode isn't safe to run.
+    Invalid(VerifyError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {} (expected {}).", version, VERSION)
+            }
+            LoadError::ChecksumMismatch => write!(f, "Checksum mismatch, the file is corrupt."),
+            LoadError::Truncated => write!(f, "Unexpected end of file."),
+            LoadError::Malformed(message) => write!(f, "Malformed bytecode: {}", message),
+            LoadError::Invalid(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub(crate) fn write(script: &ObjFunction) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    write_function(&mut out, script);
+    let checksum = checksum(&out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+fn write_function(out: &mut Vec<u8>, function: &ObjFunction) {
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(out, name.as_string().as_str());
+        }
+        None => out.push(0),
+    }
+    out.push(function.arity);
+    out.push(function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend(&chunk.code);
+
+    write_u32(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Boolean(false) => out.push(TAG_FALSE),
+            Value::Boolean(true) => out.push(TAG_TRUE),
+            Value::Double(number) => {
+                out.push(TAG_NUMBER);
+                out.extend(number.to_le_bytes());
+            }
+            Value::Object(obj) => match &obj.value {
+                HeapValue::String(string) => {
+                    out.push(TAG_STRING);
+                    write_string(out, string.as_str());
+                }
+                HeapValue::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(out, function);
+                }
+                _ => panic!("Only strings and functions can be constants."),
+            },
+        }
+    }
+
+    let runs: Vec<_> = chunk.debug_info.runs().collect();
+    write_u32(out, runs.len());
+    for (start, span) in runs {
+        write_u32(out, start);
+        write_u32(out, span.line as usize);
+        write_u32(out, span.column as usize);
+        write_u32(out, span.offset);
+        write_u32(out, span.length);
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend((value as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_u32(out, string.len());
+    out.extend(string.as_bytes());
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+// Loads a script written by `write`, allocating its functions and strings on
+// the VM's heap.
+pub(crate) fn read(vm: &mut VM, bytes: &[u8]) -> Result<ObjRef, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    if bytes.len() < MAGIC.len() + 2 + 4 {
+        return Err(LoadError::Truncated);
+    }
+    let (body, checksum_bytes) = bytes.split_at(bytes.len() - 4);
+    let version = u16::from_le_bytes([body[4], body[5]]);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    if checksum(body).to_le_bytes() != checksum_bytes {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader {
+        vm,
+        bytes: body,
+        position: MAGIC.len() + 2,
+        roots: vec![],
+        depth: 0,
+    };
+    let script = reader.function()?;
+    if script.name.is_some() {
+        return Err(LoadError::Malformed("the top-level function has a name"));
+    }
+    // The VM calls the script with no arguments and nothing to capture.
+    if script.arity != 0 {
+        return Err(LoadError::Malformed("the top-level function takes parameters"));
+    }
+    if script.upvalue_count != 0 {
+        return Err(LoadError::Malformed("the top-level function captures variables"));
+    }
+    if reader.position != body.len() {
+        return Err(LoadError::Malformed("trailing bytes after the script"));
+    }
+    Ok(reader.alloc(HeapValue::Function(script)))
+}
+
+struct Reader<'a> {
+    vm: &'a mut VM,
+    bytes: &'a [u8],
+    position: usize,
+    // Every object loaded so far. Functions still being read aren't
+    // reachable from anywhere else, so these are marked if loading collects.
+    roots: Vec<ObjRef>,
+    // How many functions enclose the one being read.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn function(&mut self) -> Result<ObjFunction, LoadError> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(LoadError::Malformed("invalid function name flag")),
+        };
+        let arity = self.u8()?;
+        let upvalue_count = self.u8()?;
+
+        let code_length = self.u32()?;
+        let code = self.take(code_length)?.to_vec();
+
+        let constant_count = self.u32()?;
+        let mut constants = Vec::with_capacity(constant_count.min(self.bytes.len()));
+        for _ in 0..constant_count {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                TAG_NUMBER => {
+                    let bytes = self.take(8)?;
+                    Value::Double(f64::from_le_bytes(bytes.try_into().unwrap()))
+                }
+                TAG_STRING => Value::Object(self.string()?),
+                TAG_FUNCTION => {
+                    if self.depth == FUNCTION_DEPTH_MAX {
+                        return Err(LoadError::Malformed("functions nested too deeply"));
+                    }
+                    self.depth += 1;
+                    let function = self.function()?;
+                    self.depth -= 1;
+                    Value::Object(self.alloc(HeapValue::Function(function)))
+                }
+                _ => return Err(LoadError::Malformed("unknown constant tag")),
+            };
+            constants.push(constant);
+        }
+
+        let run_count = self.u32()?;
+        let mut runs = Vec::with_capacity(run_count.min(self.bytes.len()));
+        for _ in 0..run_count {
+            let start = self.u32()?;
+            let span = Span {
+                line: self.u32()? as u32,
+                column: self.u32()? as u32,
+                offset: self.u32()?,
+                length: self.u32()?,
+            };
+            runs.push((start, span));
+        }
+        // Span lookups rely on the runs covering the code in order.
+        let starts_at_zero = runs.first().is_none_or(|(start, _)| *start == 0);
+        let ascending = runs.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        let covers_code = code.is_empty() || runs.last().is_some_and(|(start, _)| *start < code.len());
+        if !starts_at_zero || !ascending || !covers_code {
+            return Err(LoadError::Malformed("debug info doesn't match the code"));
+        }
+
+        let function = ObjFunction {
+            arity,
+            upvalue_count,
+            chunk: Chunk {
+                code,
+                debug_info: DebugInfo::from_runs(runs),
+                constants,
+                source: None,
+            },
+            name,
+        };
+        verifier::verify(&function).map_err(LoadError::Invalid)?;
+        Ok(function)
+    }
+
+    fn alloc(&mut self, value: HeapValue) -> ObjRef {
+        let roots = &self.roots;
+        let obj = self
+            .vm
+            .alloc_with_roots(value, |heap| mark_all(heap, roots));
+        self.roots.push(obj);
+        obj
+    }
+
+    fn string(&mut self) -> Result<ObjRef, LoadError> {
+        let length = self.u32()?;
+        let bytes = self.take(length)?;
+        let string = std::str::from_utf8(bytes)
+            .map_err(|_| LoadError::Malformed("string constant isn't valid UTF-8"))?;
+        let roots = &self.roots;
+        let obj = self
+            .vm
+            .intern_with_roots(string, |heap| mark_all(heap, roots));
+        self.roots.push(obj);
+        Ok(obj)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(LoadError::Truncated)?;
+        let bytes = self.bytes;
+        self.position = end;
+        Ok(&bytes[end - length..end])
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+}
+
+fn mark_all(heap: &mut Heap, roots: &[ObjRef]) {
+    for root in roots {
+        heap.mark_object(*root);
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::{self, Write};
+
+use crate::common::{Chunk, FmtWriter};
+use crate::host::Value;
+use crate::compiler::Token;
+
+// Hooks for watching the compiler and the VM at work. Every hook does
+// nothing by default, so implementations only override what they need.
+pub trait Tracer {
+    // Called for every token the compiler reads, including error tokens.
+    fn token(&mut self, _token: &Token) {}
+
+    // Called with each function's chunk once it has compiled without errors.
+    fn code(&mut self, _name: &str, _chunk: &Chunk) {}
+
+    // Called before the VM executes the instruction at `offset`, with the
+    // current contents of the value stack.
+    fn instruction(&mut self, _chunk: &Chunk, _offset: usize, _stack: &[Value]) {}
+}
+
+// Prints the selected traces in the style of clox's debug output, to stdout
+// unless given another writer. Tracing is a debugging aid, so a failed write
+// is ignored rather than stopping the program.
+pub struct PrintTracer {
+    pub trace_tokens: bool,
+    pub print_code: bool,
+    pub trace_exec: bool,
+    out: Box<dyn Write>,
+    // Line of the last traced token, so repeated lines print as `|`.
+    last_line: Option<u32>,
+}
+
+impl Default for PrintTracer {
+    fn default() -> Self {
+        Self::with_output(io::stdout())
+    }
+}
+
+impl PrintTracer {
+    // A tracer with every trace turned off, writing to `out`.
+    pub fn with_output(out: impl Write + 'static) -> Self {
+        PrintTracer {
+            trace_tokens: false,
+            print_code: false,
+            trace_exec: false,
+            out: Box::new(out),
+            last_line: None,
+        }
+    }
+
+    fn write_token(&mut self, token: &Token) -> io::Result<()> {
+        if self.last_line == Some(token.line) {
+            write!(self.out, "   | ")?;
+        } else {
+            write!(self.out, "{:4} ", token.line)?;
+            self.last_line = Some(token.line);
+        }
+        writeln!(self.out, "{:?} '{}'", token.token_type, token.source)
+    }
+
+    fn write_code(&mut self, name: &str, chunk: &Chunk) -> io::Result<()> {
+        writeln!(self.out, "== {} ==", name)?;
+        write!(self.out, "{}", chunk)
+    }
+
+    fn write_instruction(&mut self, chunk: &Chunk, offset: usize, stack: &[Value]) -> io::Result<()> {
+        write!(self.out, "          ")?;
+        for value in stack {
+            write!(self.out, "[ {} ]", value)?;
+        }
+        writeln!(self.out)?;
+        chunk
+            .disassemble(&mut FmtWriter(&mut self.out), offset)
+            .map(|_| ())
+            .map_err(io::Error::other)
+    }
+}
+
+impl Tracer for PrintTracer {
+    fn token(&mut self, token: &Token) {
+        if self.trace_tokens {
+            let _ = self.write_token(token);
+        }
+    }
+
+    fn code(&mut self, name: &str, chunk: &Chunk) {
+        if self.print_code {
+            let _ = self.write_code(name, chunk);
+        }
+    }
+
+    fn instruction(&mut self, chunk: &Chunk, offset: usize, stack: &[Value]) {
+        if self.trace_exec {
+            let _ = self.write_instruction(chunk, offset, stack);
+        }
+    }
+}
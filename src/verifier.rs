@@ -0,0 +1,216 @@
+// Checks that a function loaded from outside the compiler is safe to run.
+// The VM trusts its bytecode: it reads operands through a raw instruction
+// pointer and indexes constants and stack slots without bounds checks, so
+// every chunk from a `.loxc` file goes through here first.
+
+use std::fmt;
+
+use crate::common::{Chunk, HeapValue, Instruction, ObjFunction, OpCode, Operand, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    // None for the top-level script.
+    pub function: Option<String>,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "Invalid bytecode in {}() ", name)?,
+            None => write!(f, "Invalid bytecode in script ")?,
+        }
+        write!(f, "at offset {}: {}", self.offset, self.message)
+    }
+}
+
+// Verifies `function`'s own chunk. Nested functions in its constants are
+// verified separately, when they're loaded.
+pub(crate) fn verify(function: &ObjFunction) -> Result<(), VerifyError> {
+    let error = |offset: usize, message: String| VerifyError {
+        function: function.name.map(|name| name.as_string().as_str().to_owned()),
+        offset,
+        message,
+    };
+    let chunk = &function.chunk;
+    if chunk.code.is_empty() {
+        return Err(error(0, "Function has no code.".to_owned()));
+    }
+
+    // Decode everything up front so jumps can be checked against
+    // instruction boundaries.
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let instruction = chunk.decode(offset).map_err(|message| error(offset, message))?;
+        check_operands(function, &instruction).map_err(|message| error(offset, message))?;
+        offset += instruction.length;
+        instructions.push(instruction);
+    }
+    let index_of = |offset: usize| {
+        instructions
+            .binary_search_by_key(&offset, |instruction| instruction.offset)
+            .ok()
+    };
+
+    // Follow every path through the code, tracking how many values each
+    // instruction finds on the stack. Paths that meet must agree, so the
+    // depth at each instruction is known statically.
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+    // Slot 0 holds the callee, followed by the arguments.
+    let mut pending = vec![(0, function.arity as usize + 1)];
+    while let Some((index, depth)) = pending.pop() {
+        let instruction = &instructions[index];
+        match depths[index] {
+            Some(seen) if seen == depth => continue,
+            Some(seen) => {
+                let message = format!(
+                    "Stack depth is {} on one path here and {} on another.",
+                    seen, depth
+                );
+                return Err(error(instruction.offset, message));
+            }
+            None => depths[index] = Some(depth),
+        }
+
+        let (pops, pushes) = stack_effect(instruction);
+        if depth < pops {
+            let message = format!(
+                "{:?} needs {} values but the stack only has {}.",
+                instruction.opcode, pops, depth
+            );
+            return Err(error(instruction.offset, message));
+        }
+        check_slots(function, instruction, depth)
+            .map_err(|message| error(instruction.offset, message))?;
+        let depth = depth - pops + pushes;
+
+        let next = instruction.offset + instruction.length;
+        let successors = match (instruction.opcode, &instruction.operand) {
+            (OpCode::Return, _) => vec![],
+            (OpCode::Jump | OpCode::Loop, Operand::Jump(target)) => vec![*target],
+            (OpCode::JumpIfFalse, Operand::Jump(target)) => vec![next, *target],
+            _ => vec![next],
+        };
+        for successor in successors {
+            if successor == chunk.code.len() {
+                return Err(error(instruction.offset, "Execution runs past the end of the code.".to_owned()));
+            }
+            match index_of(successor) {
+                Some(index) => pending.push((index, depth)),
+                None => {
+                    let message = format!("Jump to {} isn't the start of an instruction.", successor);
+                    return Err(error(instruction.offset, message));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Checks that constant operands exist and have the type the VM expects.
+fn check_operands(function: &ObjFunction, instruction: &Instruction) -> Result<(), String> {
+    let chunk = &function.chunk;
+    let constant = match instruction.operand {
+        Operand::Constant(constant)
+        | Operand::Invoke { constant, .. }
+        | Operand::Closure { constant, .. } => constant,
+        _ => return Ok(()),
+    };
+    if constant >= chunk.constants.len() {
+        return Err(format!("Constant {} is out of range.", constant));
+    }
+    let needs_string = !matches!(
+        instruction.opcode,
+        OpCode::Constant | OpCode::ConstantLong | OpCode::Closure
+    );
+    if needs_string && !is_string(chunk, constant) {
+        return Err(format!("{:?} expects a string constant.", instruction.opcode));
+    }
+    Ok(())
+}
+
+fn is_string(chunk: &Chunk, constant: usize) -> bool {
+    match chunk.constants[constant] {
+        Value::Object(obj) => matches!(obj.value, HeapValue::String(_)),
+        _ => false,
+    }
+}
+
+// Checks that locals and upvalues refer to slots that exist.
+fn check_slots(function: &ObjFunction, instruction: &Instruction, depth: usize) -> Result<(), String> {
+    let upvalue_count = function.upvalue_count as usize;
+    match (instruction.opcode, &instruction.operand) {
+        (OpCode::GetLocal | OpCode::SetLocal, Operand::Slot(slot)) if *slot as usize >= depth => {
+            Err(format!("Local slot {} is beyond the stack.", slot))
+        }
+        (OpCode::GetUpvalue | OpCode::SetUpvalue, Operand::Slot(slot))
+            if *slot as usize >= upvalue_count =>
+        {
+            Err(format!("Upvalue {} doesn't exist.", slot))
+        }
+        (OpCode::Closure, Operand::Closure { upvalues, .. }) => {
+            for (is_local, index) in upvalues {
+                if *is_local && *index as usize >= depth {
+                    return Err(format!("Captured local slot {} is beyond the stack.", index));
+                }
+                if !*is_local && *index as usize >= upvalue_count {
+                    return Err(format!("Captured upvalue {} doesn't exist.", index));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// How many values the instruction pops, and then pushes.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    match (instruction.opcode, &instruction.operand) {
+        (OpCode::Call, Operand::ArgCount(args)) => (*args as usize + 1, 1),
+        (OpCode::Invoke, Operand::Invoke { arg_count, .. }) => (*arg_count as usize + 1, 1),
+        // The superclass is on top of the receiver and arguments.
+        (OpCode::SuperInvoke, Operand::Invoke { arg_count, .. }) => (*arg_count as usize + 2, 1),
+        (opcode, _) => match opcode {
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetLocal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Class => (0, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::SetGlobal
+            | OpCode::SetLocal
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            // Leaves the condition on the stack.
+            | OpCode::JumpIfFalse => (1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::FloorDivide
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::SetProperty
+            // Pops the superclass and replaces the receiver.
+            | OpCode::GetSuper => (2, 1),
+            OpCode::Return | OpCode::Print | OpCode::Pop | OpCode::DefineGlobal | OpCode::CloseUpvalue => (1, 0),
+            // Pops the method closure and leaves the class.
+            OpCode::Method => (2, 1),
+            // Pops the subclass and leaves the superclass.
+            OpCode::Inherit => (2, 1),
+            OpCode::Jump | OpCode::Loop => (0, 0),
+            OpCode::Call | OpCode::Invoke | OpCode::SuperInvoke => unreachable!(),
+        },
+    }
+}
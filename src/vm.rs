@@ -1,105 +1,325 @@
 use std::{
     cell::RefCell,
-    fs,
-    io::{self, BufRead, Write},
-    rc::Rc,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::common::Table;
-use crate::common::{BoxedObjString, Chunk, Obj, OpCode};
-use crate::common::{HeapValue, Value};
+use crate::common::{BoxedObjString, Chunk, Heap, HeapValue, ObjRef, OpCode, Trace, Value};
+use crate::common::{RuntimeError, StackFrame};
+use crate::common::{NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative, ObjUpvalue};
 
 use crate::compiler::*;
+use crate::disassembler::{self, DisassemblyFormat};
+use crate::host;
+use crate::serialize::{self, LoadError};
+use crate::tracer::Tracer;
 
-#[cfg(debug_assertions)]
-use crate::common::FmtWriter;
 
 #[derive(Debug, PartialEq)]
 pub enum InterpretError {
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    // A compiled script couldn't be loaded.
+    LoadError(LoadError),
 }
+
+struct CallFrame {
+    // Always a HeapValue::Closure.
+    closure: ObjRef,
+    // Only kept up to date for frames that aren't executing; the running
+    // frame's instruction pointer lives in `VM::ip`.
+    ip: *const u8,
+    // Index of the frame's first stack slot (the callee itself).
+    slots: usize,
+}
+
 pub struct VM {
-    chunk: Rc<RefCell<Chunk>>,
+    frames: Vec<CallFrame>,
     // TODO: can we make this better?
     ip: *const u8,
-    stack: [Value; STACK_MAX],
-    // TODO: revisit this too.. is there a point?
-    stack_idx: usize,
-    objects: Option<*const Obj>,
-    strings: Table<BoxedObjString, ()>,
+    // Grows as needed, up to `stack_limit` values.
+    stack: Vec<Value>,
+    stack_limit: usize,
+    // How many calls may be in progress at once.
+    frame_limit: usize,
+    heap: Heap,
+    // Upvalues still pointing into the stack, ordered by stack slot.
+    open_upvalues: Vec<ObjRef>,
+    globals: Table<ObjRef, Value>,
+    // Every live string object, so each distinct string is only allocated
+    // once. Entries don't keep strings alive; unreachable ones are dropped
+    // from the table before sweeping.
+    strings: Table<ObjRef, ()>,
+    init_string: ObjRef,
+    tracer: Option<Box<dyn Tracer>>,
+    // Where `print` statements write.
+    stdout: Box<dyn Write>,
+    // Where `report` writes errors.
+    stderr: Box<dyn Write>,
 }
 
-const STACK_MAX: usize = 256;
+// The default frame limit.
+const FRAMES_MAX: usize = 64;
+// The default stack limit: enough for every frame to use all 256 of its
+// local slots.
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
 type BinaryOp<I, O> = fn(I, I) -> O;
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
-    pub fn init() -> Self {
-        
-        VM {
-            chunk: Rc::new(RefCell::new(Chunk::new())),
+    // A VM that prints to the process's stdout and stderr.
+    pub fn new() -> Self {
+        Self::with_output(io::stdout(), io::stderr())
+    }
+
+    // A VM that sends program output to `stdout` and reported errors to
+    // `stderr`, so hosts can capture, redirect or discard them.
+    pub fn with_output(stdout: impl Write + 'static, stderr: impl Write + 'static) -> Self {
+        let mut heap = Heap::new();
+        let mut strings = Table::new();
+        let init_string = heap.alloc(HeapValue::String(BoxedObjString::of_ref("init")));
+        strings.set(&init_string, ());
+
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
             ip: std::ptr::null_mut(),
-            stack: std::array::from_fn(|_| Value::Nil),
-            // stack_top: std::ptr::null_mut(),
-            stack_idx: 0,
-            objects: None,
-            strings: Table::new(),
+            stack: Vec::with_capacity(u8::MAX as usize + 1),
+            stack_limit: STACK_MAX,
+            frame_limit: FRAMES_MAX,
+            heap,
+            open_upvalues: vec![],
+            globals: Table::new(),
+            strings,
+            init_string,
+            tracer: None,
+            stdout: Box::new(stdout),
+            stderr: Box::new(stderr),
+        };
+        vm.define_native("clock", 0, clock_native);
+        vm
+    }
+
+    // Makes a Rust function callable from Lox as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let name = self.intern(name);
+        let native = self.alloc(HeapValue::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.globals.set(&name, Value::Object(native));
+    }
+
+    // A script fails with a "Stack overflow." runtime error when it would
+    // exceed either limit: the most values the stack may hold, or the most
+    // calls that may be in progress at once. Deep recursion needs both
+    // raised, since every call uses at least one stack slot.
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack_limit = limit;
+    }
+
+    pub fn set_frame_limit(&mut self, limit: usize) {
+        self.frame_limit = limit;
+    }
+
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.heap.set_stress_gc(stress_gc);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    pub(crate) fn tracer(&mut self) -> Option<&mut (dyn Tracer + 'static)> {
+        self.tracer.as_deref_mut()
+    }
+
+    // Compiles and runs `source` as a script. Globals it defines stay around
+    // for later calls.
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        self.interpret_with_mode(source, CompileMode::Script)
+    }
+
+    // Like `interpret`, compiling `source` in the given mode.
+    pub fn interpret_with_mode(&mut self, source: &str, mode: CompileMode) -> Result<(), InterpretError> {
+        let function = compile(source.to_owned(), mode, self).map_err(InterpretError::CompileError)?;
+        self.run_script(function)
+    }
+
+    // Compiles `source` to the `.loxc` format without running it.
+    pub fn compile_bytecode(&mut self, source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let function = compile(source.to_owned(), CompileMode::Script, self)?;
+        Ok(serialize::write(function.as_function()))
+    }
+
+    // Loads and runs a script produced by `compile_bytecode`.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let function = serialize::read(self, bytes).map_err(InterpretError::LoadError)?;
+        self.run_script(function)
+    }
+
+    // Lists the bytecode of every function in `source`.
+    pub fn disassemble(
+        &mut self,
+        source: &str,
+        mode: CompileMode,
+        format: DisassemblyFormat,
+    ) -> Result<String, Vec<Diagnostic>> {
+        let function = compile(source.to_owned(), mode, self)?;
+        Ok(disassembler::disassemble(function, format))
+    }
+
+    // Like `disassemble`, for a script produced by `compile_bytecode`.
+    pub fn disassemble_bytecode(
+        &mut self,
+        bytes: &[u8],
+        format: DisassemblyFormat,
+    ) -> Result<String, LoadError> {
+        let function = serialize::read(self, bytes)?;
+        Ok(disassembler::disassemble(function, format))
+    }
+
+    // The VM's output writers, for hosts like the REPL that print alongside
+    // the program.
+    pub(crate) fn stdout(&mut self) -> &mut dyn Write {
+        &mut *self.stdout
+    }
+
+    pub(crate) fn stderr(&mut self) -> &mut dyn Write {
+        &mut *self.stderr
+    }
+
+    // Writes `error` to the VM's stderr the way the rlox command line
+    // reports it.
+    pub fn report(&mut self, error: &InterpretError) {
+        // Output printed before the error should appear before it.
+        let _ = self.stdout.flush();
+        let _ = match error {
+            InterpretError::CompileError(diagnostics) => diagnostics
+                .iter()
+                .try_for_each(|diagnostic| writeln!(self.stderr, "{}", diagnostic)),
+            InterpretError::RuntimeError(error) => writeln!(self.stderr, "{}", error),
+            InterpretError::LoadError(error) => writeln!(self.stderr, "{}", error),
+        };
+        let _ = self.stderr.flush();
+    }
+
+    fn run_script(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        self.push(Value::Object(function));
+        let closure = self.alloc(HeapValue::Closure(ObjClosure {
+            function,
+            upvalues: vec![],
+        }));
+        self.pop();
+        self.push(Value::Object(closure));
+        if let Err(error) = self.call_closure(closure, 0).and_then(|_| self.run(0)) {
+            self.unwind(0, 0);
+            return Err(error);
         }
+        // The script's implicit nil return value.
+        self.pop();
+        if let Err(e) = self.stdout.flush() {
+            return Err(self.runtime_error(&format!("Couldn't write output: {}", e)));
+        }
+        Ok(())
     }
-    pub fn repl(&mut self) {
-        let stdin = io::stdin();
-        let mut iterator = stdin.lock().lines();
-        loop {
-            // let mut buffer = String::new();
-            print!(">");
-            io::stdout().flush().unwrap();
-            // let result = { stdin.read_line(&mut buffer) };
-            match iterator.next() {
-                // Ok(bytes_read) if bytes_read == 0 => {
-                Some(Ok(line)) if line.is_empty() => {
-                    println!();
-                    break;
-                }
-                Some(Ok(line)) => {
-                    // TODO: exception handling here
-                    self.interpret(line)
-                        .unwrap_or_else(|_| println!("Error executing REPL"));
-                }
-                Some(Err(err)) => {
-                    println!("{}", err);
-                    break;
-                }
-                None => {
-                    break;
-                }
-            }
+
+    // Calls the global function, class or native `name` and returns its
+    // result. Natives may use this to call back into Lox, and carry on if it
+    // fails: a runtime error only unwinds what the call itself added.
+    pub fn call(&mut self, name: &str, args: &[host::Value]) -> Result<host::Value, InterpretError> {
+        let stack_len = self.stack.len();
+        let frame_count = self.frames.len();
+        let ip = self.ip;
+        let result = self.call_global(name, args);
+        if result.is_err() {
+            self.unwind(stack_len, frame_count);
+            self.ip = ip;
         }
+        result
     }
 
-    pub fn run_file(&mut self, path: &String) {
-        println!("Readind path {}", path);
-        let contents = fs::read_to_string(path);
-        match contents {
-            Ok(contents) => {
-                match self.interpret(contents) {
-                    Ok(()) => (),
-                    Err(InterpretError::CompileError) => std::process::exit(65),
-                    Err(InterpretError::RuntimeError) => std::process::exit(70),
-                };
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => println!("File not found."),
-            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
-                println!("Permission denied reading file.")
-            }
-            Err(e) => println!("Unexpected error reading file. {:?}", e),
+    fn call_global(&mut self, name: &str, args: &[host::Value]) -> Result<host::Value, InterpretError> {
+        let Some(callee) = self.global(name) else {
+            let message = format!("Undefined variable '{}'.", name);
+            return Err(self.runtime_error(&message));
+        };
+        let Ok(arg_count) = u8::try_from(args.len()) else {
+            return Err(self.runtime_error("Can't have more than 255 arguments."));
+        };
+
+        if self.stack.len() + args.len() >= self.stack_limit {
+            return Err(self.runtime_error("Stack overflow."));
         }
+
+        let frame_count = self.frames.len();
+        self.push(callee);
+        for arg in args {
+            // Each argument is pushed before the next is converted, since
+            // converting a string may collect garbage.
+            let arg = self.value_from_host(arg)?;
+            self.push(arg);
+        }
+        self.call_value(callee, arg_count)?;
+        // Natives and classes without an initializer complete immediately.
+        if self.frames.len() > frame_count {
+            self.run(frame_count)?;
+        }
+        let result = host::Value::from_vm(*self.peek(0));
+        self.pop();
+        Ok(result)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<host::Value> {
+        self.global(name).map(host::Value::from_vm)
+    }
+
+    fn global(&self, name: &str) -> Option<Value> {
+        let name = self.find_string(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    // Every global variable, sorted by name.
+    pub fn globals(&self) -> Vec<(String, host::Value)> {
+        let mut globals: Vec<(String, host::Value)> = self
+            .globals
+            .iter()
+            .map(|(name, value)| (name.as_string().as_str().to_owned(), host::Value::from_vm(*value)))
+            .collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
     }
 
-    pub fn interpret(&mut self, program: String) -> Result<(), InterpretError> {
-        compile(program, self.chunk.clone())?;
-        self.ip = &self.chunk.borrow_mut().code[0];
-        self.run()
+    // Fails if `value` is an object, since those can't be passed back in.
+    pub fn set_global(&mut self, name: &str, value: &host::Value) -> Result<(), InterpretError> {
+        let value = self.value_from_host(value)?;
+        // Keep the value reachable in case interning the name collects.
+        self.push(value);
+        let name = self.intern(name);
+        self.globals.set(&name, value);
+        self.pop();
+        Ok(())
+    }
+
+    // Copies a host value into the VM, allocating strings on its heap.
+    fn value_from_host(&mut self, value: &host::Value) -> Result<Value, InterpretError> {
+        match value {
+            host::Value::Double(val) => Ok(Value::Double(*val)),
+            host::Value::Boolean(val) => Ok(Value::Boolean(*val)),
+            host::Value::Nil => Ok(Value::Nil),
+            host::Value::String(string) => Ok(Value::Object(self.intern(string))),
+            host::Value::Object(description) => {
+                let message = format!("Can't pass {} into Lox.", description);
+                Err(self.runtime_error(&message))
+            }
+        }
     }
 
     // pub fn interpret(&mut self, chunk: &'a Chunk) -> Result<(), InterpretError> {
@@ -108,28 +328,234 @@ impl VM {
     //     self.run()
     // }
 
-    fn run(&mut self) -> Result<(), InterpretError> {
+    // Executes instructions until the frame count drops back to
+    // `frame_count`, leaving the returned value on the stack.
+    fn run(&mut self, frame_count: usize) -> Result<(), InterpretError> {
         loop {
-            // FIXME: do not unwrap...
-            #[cfg(debug_assertions)]
-            {
-                self.print_stack();
-
-                let chunk = self.chunk.borrow_mut();
-                let start_ptr = &chunk.code[0] as *const u8;
-                chunk
-                    .disassemble(
-                        &mut FmtWriter(std::io::stdout()),
-                        (self.ip as usize) - (start_ptr as usize),
-                    )
-                    .unwrap();
+            if let Some(mut tracer) = self.tracer.take() {
+                let chunk = self.chunk();
+                let offset = (self.ip as usize) - (chunk.code.as_ptr() as usize);
+                let stack: Vec<host::Value> = self.stack.iter().map(|value| host::Value::from_vm(*value)).collect();
+                tracer.instruction(chunk, offset, &stack);
+                self.tracer = Some(tracer);
             }
 
-            let opcode = OpCode::from(self.read_byte());
+            // Compiled chunks are valid by construction, and loaded ones
+            // have been verified.
+            let opcode = OpCode::try_from(self.read_byte()).unwrap();
+            // No instruction pushes more than one value, so this keeps the
+            // stack within its limit.
+            if self.stack.len() >= self.stack_limit {
+                return Err(self.runtime_error("Stack overflow."));
+            }
             match opcode {
                 OpCode::Return => {
-                    println!("{}", self.pop());
-                    return Ok(());
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
+                    if let Some(caller) = self.frames.last() {
+                        self.ip = caller.ip;
+                    }
+                    if self.frames.len() == frame_count {
+                        return Ok(());
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    let callee = *self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Print => {
+                    let value = self.pop();
+                    if let Err(e) = writeln!(self.stdout, "{}", value) {
+                        return Err(self.runtime_error(&format!("Couldn't write output: {}", e)));
+                    }
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant() {
+                        Value::Object(function) => function,
+                        _ => panic!("Expected function constant operand."),
+                    };
+                    let upvalue_count = function.as_function().upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count as usize);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let frame = self.frames.last().unwrap();
+                        let upvalue = if is_local {
+                            let slot = frame.slots + index;
+                            self.capture_upvalue(slot)
+                        } else {
+                            frame.closure.as_closure().upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(HeapValue::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Object(closure));
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frames.last().unwrap().closure.as_closure().upvalues[slot];
+                    let value = match *upvalue.as_upvalue().borrow() {
+                        ObjUpvalue::Open(stack_slot) => self.stack[stack_slot],
+                        ObjUpvalue::Closed(value) => value,
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frames.last().unwrap().closure.as_closure().upvalues[slot];
+                    let value = *self.peek(0);
+                    match &mut *upvalue.as_upvalue().borrow_mut() {
+                        ObjUpvalue::Open(stack_slot) => self.stack[*stack_slot] = value,
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    let class = self.alloc(HeapValue::Class(RefCell::new(ObjClass {
+                        name,
+                        methods: Table::new(),
+                    })));
+                    self.push(Value::Object(class));
+                }
+                OpCode::Method => {
+                    let name = self.read_string();
+                    self.define_method(name)?;
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek(0) {
+                        Value::Object(obj) if matches!(obj.value, HeapValue::Instance(_)) => *obj,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let HeapValue::Instance(instance) = &instance.value else {
+                        unreachable!()
+                    };
+
+                    let field = instance.borrow().fields.get(&name).copied();
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            let class = instance.borrow().class;
+                            self.bind_method(class, &name)?;
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    let instance = match self.peek(1) {
+                        Value::Object(obj) if matches!(obj.value, HeapValue::Instance(_)) => *obj,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let HeapValue::Instance(instance) = &instance.value else {
+                        unreachable!()
+                    };
+
+                    let value = self.pop();
+                    instance.borrow_mut().fields.set(&name, value);
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Object(obj) if matches!(obj.value, HeapValue::Class(_)) => *obj,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    let subclass = self.pop_class()?;
+                    // Only reachable from loaded bytecode; the compiler
+                    // rejects a class inheriting from itself.
+                    if subclass == superclass {
+                        return Err(self.runtime_error("A class can't inherit from itself."));
+                    }
+                    // Copy-down inheritance: methods defined in the subclass
+                    // body are added afterwards and override these.
+                    superclass
+                        .as_class()
+                        .borrow()
+                        .methods
+                        .add_all(&mut subclass.as_class().borrow_mut().methods);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, &name)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte();
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, &name, arg_count)?;
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let arg_count = self.read_byte();
+                    self.invoke(&name, arg_count)?;
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame_slot();
+                    let value = self.stack[slot];
+                    self.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame_slot();
+                    self.stack[slot] = *self.peek(0);
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.ip = unsafe { self.ip.add(offset as usize) };
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.ip = unsafe { self.ip.add(offset as usize) };
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.ip = unsafe { self.ip.sub(offset as usize) };
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    let value = *self.peek(0);
+                    self.globals.set(&name, value);
+                    self.pop();
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    match self.globals.get(&name) {
+                        Some(value) => {
+                            let value = *value;
+                            self.push(value);
+                        }
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name.as_string().as_str());
+                            return Err(self.runtime_error(&message));
+                        }
+                    }
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    let value = *self.peek(0);
+                    // Assignment never creates a global, so undo the insert.
+                    if self.globals.set(&name, value) {
+                        self.globals.delete(&name);
+                        let message = format!("Undefined variable '{}'.", name.as_string().as_str());
+                        return Err(self.runtime_error(&message));
+                    }
                 }
                 OpCode::Constant => {
                     let constant = self.read_constant();
@@ -140,27 +566,25 @@ impl VM {
                     self.push(constant);
                 }
                 OpCode::Negate => {
+                    if !matches!(self.peek(0), Value::Double(_)) {
+                        return Err(self.runtime_error("Operand must be a number."));
+                    }
                     let negated = -self.pop();
                     self.push(negated);
                 }
-                OpCode::Add => match (self.peek(0), self.peek(1)) {
-                    (
-                        Value::Object(Obj {
-                            value: HeapValue::String(_),
-                            next: _,
-                        }),
-                        Value::Object(Obj {
-                            value: HeapValue::String(_),
-                            next: _,
-                        }),
-                    ) => {
+                OpCode::Add => {
+                    if Self::is_string(self.peek(0)) && Self::is_string(self.peek(1)) {
                         self.string_concat()?;
+                    } else {
+                        self.arithmetic_op(opcode)?;
                     }
-                    (_, _) => self.binary_op(|a, b| Value::Double(a + b))?,
-                },
-                OpCode::Subtract => self.binary_op(|a, b| Value::Double(a - b))?,
-                OpCode::Multiply => self.binary_op(|a, b| Value::Double(a * b))?,
-                OpCode::Divide => self.binary_op(|a, b| Value::Double(a / b))?,
+                }
+                OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Modulo
+                | OpCode::Power
+                | OpCode::FloorDivide => self.arithmetic_op(opcode)?,
                 OpCode::Nil => self.push(Value::Nil),
                 OpCode::True => self.push(Value::Boolean(true)),
                 OpCode::False => self.push(Value::Boolean(false)),
@@ -180,6 +604,287 @@ impl VM {
         }
     }
 
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), InterpretError> {
+        let Value::Object(obj) = callee else {
+            return Err(self.runtime_error("Can only call functions and classes."));
+        };
+        match &obj.value {
+            HeapValue::Closure(_) => self.call_closure(obj, arg_count),
+            HeapValue::BoundMethod(bound) => {
+                *self.peek_mut(arg_count as usize) = bound.receiver;
+                self.call_closure(bound.method, arg_count)
+            }
+            HeapValue::Class(class) => {
+                let initializer = class.borrow().methods.get(&self.init_string).copied();
+                // The class stays reachable from the callee slot until the
+                // instance replaces it.
+                let instance = self.alloc(HeapValue::Instance(RefCell::new(ObjInstance {
+                    class: obj,
+                    fields: Table::new(),
+                })));
+                *self.peek_mut(arg_count as usize) = Value::Object(instance);
+
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        let message = format!("Expected 0 arguments but got {}.", arg_count);
+                        Err(self.runtime_error(&message))
+                    }
+                    None => Ok(()),
+                }
+            }
+            HeapValue::Native(native) => {
+                if arg_count != native.arity {
+                    let message =
+                        format!("Expected {} arguments but got {}.", native.arity, arg_count);
+                    return Err(self.runtime_error(&message));
+                }
+
+                // Copied out since the native gets the whole VM mutably.
+                let args: Vec<host::Value> = self.stack[self.stack.len() - arg_count as usize..]
+                    .iter()
+                    .map(|arg| host::Value::from_vm(*arg))
+                    .collect();
+                match (native.function)(self, &args) {
+                    Ok(result) => {
+                        let result = self.value_from_host(&result)?;
+                        self.stack.truncate(self.stack.len() - arg_count as usize - 1);
+                        self.push(result);
+                        Ok(())
+                    }
+                    Err(error) => Err(self.runtime_error(&error.message)),
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
+    }
+
+    fn invoke(&mut self, name: &ObjRef, arg_count: u8) -> Result<(), InterpretError> {
+        let receiver = *self.peek(arg_count as usize);
+        let instance = match &receiver {
+            Value::Object(obj) => match &obj.value {
+                HeapValue::Instance(instance) => instance,
+                _ => return Err(self.runtime_error("Only instances have methods.")),
+            },
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+
+        // A field holding a callable shadows a method of the same name.
+        let field = instance.borrow().fields.get(name).copied();
+        if let Some(value) = field {
+            *self.peek_mut(arg_count as usize) = value;
+            return self.call_value(value, arg_count);
+        }
+
+        let class = instance.borrow().class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: &ObjRef,
+        arg_count: u8,
+    ) -> Result<(), InterpretError> {
+        let method = class.as_class().borrow().methods.get(name).copied();
+        match method {
+            Some(method) => self.call_closure(method, arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", name.as_string().as_str());
+                Err(self.runtime_error(&message))
+            }
+        }
+    }
+
+    // Replaces the instance on top of the stack with its method `name` bound
+    // to it.
+    fn bind_method(&mut self, class: ObjRef, name: &ObjRef) -> Result<(), InterpretError> {
+        let method = class.as_class().borrow().methods.get(name).copied();
+        let Some(method) = method else {
+            let message = format!("Undefined property '{}'.", name.as_string().as_str());
+            return Err(self.runtime_error(&message));
+        };
+
+        // The receiver stays on the stack while the bound method is allocated.
+        let receiver = *self.peek(0);
+        let bound = self.alloc(HeapValue::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.pop();
+        self.push(Value::Object(bound));
+        Ok(())
+    }
+
+    // The compiler only emits these with a class on the stack, but loaded
+    // bytecode isn't held to that.
+    fn pop_class(&mut self) -> Result<ObjRef, InterpretError> {
+        match self.pop() {
+            Value::Object(class) if matches!(class.value, HeapValue::Class(_)) => Ok(class),
+            _ => Err(self.runtime_error("Expected a class.")),
+        }
+    }
+
+    fn define_method(&mut self, name: ObjRef) -> Result<(), InterpretError> {
+        match (self.peek(1), self.peek(0)) {
+            (Value::Object(class), Value::Object(method))
+                if matches!(class.value, HeapValue::Class(_))
+                    && matches!(method.value, HeapValue::Closure(_)) =>
+            {
+                class.as_class().borrow_mut().methods.set(&name, *method);
+            }
+            _ => return Err(self.runtime_error("Methods can only be closures defined on a class.")),
+        }
+        self.pop();
+        Ok(())
+    }
+
+    fn call_closure(&mut self, closure: ObjRef, arg_count: u8) -> Result<(), InterpretError> {
+        let function = closure.as_closure().function;
+        let arity = function.as_function().arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Err(self.runtime_error(&message));
+        }
+        if self.frames.len() >= self.frame_limit {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = self.ip;
+        }
+        self.ip = function.as_function().chunk.code.as_ptr();
+        self.frames.push(CallFrame {
+            closure,
+            ip: self.ip,
+            slots: self.stack.len() - arg_count as usize - 1,
+        });
+        Ok(())
+    }
+
+    // Returns the upvalue for a stack slot, reusing an open one if another
+    // closure already captured the same variable.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_idx = self.open_upvalues.len();
+        for (idx, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            let open_slot = Self::open_upvalue_slot(*upvalue);
+            if open_slot == slot {
+                return *upvalue;
+            }
+            if open_slot < slot {
+                break;
+            }
+            insert_idx = idx;
+        }
+
+        let upvalue = self.alloc(HeapValue::Upvalue(RefCell::new(ObjUpvalue::Open(slot))));
+        self.open_upvalues.insert(insert_idx, upvalue);
+        upvalue
+    }
+
+    // Moves every captured variable at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = Self::open_upvalue_slot(*upvalue);
+            if slot < last {
+                break;
+            }
+            *upvalue.as_upvalue().borrow_mut() = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn open_upvalue_slot(upvalue: ObjRef) -> usize {
+        match *upvalue.as_upvalue().borrow() {
+            ObjUpvalue::Open(slot) => slot,
+            ObjUpvalue::Closed(_) => panic!("Closed upvalue in open upvalue list."),
+        }
+    }
+
+    // Allocates a heap object, collecting garbage first if the heap has grown
+    // past its threshold.
+    fn alloc(&mut self, value: HeapValue) -> ObjRef {
+        self.alloc_with_roots(value, |_| ())
+    }
+
+    // Like `alloc`, but lets callers holding objects the VM can't see (such
+    // as the compiler) mark them as extra roots.
+    pub(crate) fn alloc_with_roots<F>(&mut self, value: HeapValue, mark_roots: F) -> ObjRef
+    where
+        F: FnOnce(&mut Heap),
+    {
+        if self.heap.should_collect() {
+            // Whatever the new object references isn't reachable from any
+            // root until it has been allocated.
+            value.trace(&mut self.heap);
+            mark_roots(&mut self.heap);
+            self.collect_garbage();
+        }
+        self.heap.alloc(value)
+    }
+
+    // Returns the string object with these contents, allocating it only if
+    // no such string exists yet.
+    fn intern(&mut self, string: &str) -> ObjRef {
+        self.intern_with_roots(string, |_| ())
+    }
+
+    pub(crate) fn intern_with_roots<F>(&mut self, string: &str, mark_roots: F) -> ObjRef
+    where
+        F: FnOnce(&mut Heap),
+    {
+        if let Some(interned) = self.find_string(string) {
+            return interned;
+        }
+
+        let value = HeapValue::String(BoxedObjString::of_ref(string));
+        let interned = self.alloc_with_roots(value, mark_roots);
+        self.strings.set(&interned, ());
+        interned
+    }
+
+    fn find_string(&self, string: &str) -> Option<ObjRef> {
+        let hash = BoxedObjString::hash_str(string);
+        self.strings
+            .find_key(hash, |key| key.as_string().as_str() == string)
+            .copied()
+    }
+
+    fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.heap.trace_references();
+        let heap = &self.heap;
+        self.strings.retain(|string, _| heap.is_marked(*string));
+        self.heap.sweep();
+    }
+
+    fn mark_roots(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        self.globals.trace(&mut self.heap);
+        self.heap.mark_object(self.init_string);
+    }
+
+    #[inline(always)]
+    fn chunk(&self) -> &Chunk {
+        let closure = self.frames.last().unwrap().closure;
+        let function = closure.as_closure().function;
+        // The running closure is rooted by its frame for as long as the chunk
+        // is borrowed.
+        unsafe { &*(&function.as_function().chunk as *const Chunk) }
+    }
+
+    // Reads a local slot operand and resolves it against the current frame.
+    #[inline(always)]
+    fn frame_slot(&mut self) -> usize {
+        let slot = self.read_byte() as usize;
+        self.frames.last().unwrap().slots + slot
+    }
+
     #[inline(always)]
     fn read_byte(&mut self) -> u8 {
         let ret = unsafe { *self.ip };
@@ -187,11 +892,17 @@ impl VM {
         ret
     }
 
+    #[inline(always)]
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
     #[inline(always)]
     fn read_constant(&mut self) -> Value {
         let idx = self.read_byte();
-        let val = self.chunk.borrow().constants[idx as usize].clone();
-        val
+        self.chunk().constants[idx as usize]
     }
 
     #[inline(always)]
@@ -200,38 +911,45 @@ impl VM {
         unsafe { std::ptr::copy_nonoverlapping(self.ip, &mut bytes as *mut u8, 3) };
         self.ip = unsafe { self.ip.add(3) };
         let idx = u32::from_le_bytes(bytes);
-        return self.chunk.borrow().constants[idx as usize].clone();
+        self.chunk().constants[idx as usize]
+    }
+
+    #[inline(always)]
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant() {
+            Value::Object(name) if matches!(name.value, HeapValue::String(_)) => name,
+            _ => panic!("Expected string constant operand."),
+        }
+    }
+
+    // Discards every value and frame above the given depths, as after a
+    // runtime error. Variables captured from the discarded slots are closed
+    // first, so closures that escaped keep their last values.
+    fn unwind(&mut self, stack_len: usize, frame_count: usize) {
+        self.close_upvalues(stack_len);
+        self.stack.truncate(stack_len);
+        self.frames.truncate(frame_count);
     }
 
     #[inline(always)]
-    fn reset_stack(&mut self) {
-        // self.stack_top = &mut self.stack[0];
-        self.stack_idx = 0;
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
     }
 
     #[inline(always)]
-    pub fn push(&mut self, value: Value) {
-        // TODO: Stack bounds checking/resizing
-        self.stack[self.stack_idx] = value;
-        self.stack_idx += 1;
-        // println!("{:?}", self.stack_top);
-        // unsafe { *self.stack_top = value };
-        // self.stack_top = unsafe { self.stack_top.add(1) };
-        // println!("{:?}", self.stack_top);
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
     }
 
     #[inline(always)]
-    pub fn pop(&mut self) -> Value {
-        // self.stack_top = unsafe { self.stack_top.sub(1) };
-        // let ret = unsafe { *self.stack_top };
-        // ret
-        self.stack_idx -= 1;
-        self.stack[self.stack_idx].clone()
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
     }
 
     #[inline(always)]
-    pub fn peek(&self, distance: usize) -> &Value {
-        &self.stack[self.stack_idx - 1 - distance]
+    fn peek_mut(&mut self, distance: usize) -> &mut Value {
+        let len = self.stack.len();
+        &mut self.stack[len - 1 - distance]
     }
 
     #[inline(always)]
@@ -250,44 +968,37 @@ impl VM {
         }
     }
 
+    #[inline(always)]
+    fn arithmetic_op(&mut self, opcode: OpCode) -> Result<(), InterpretError> {
+        let (Value::Double(left), Value::Double(right)) = (*self.peek(1), *self.peek(0)) else {
+            return Err(self.runtime_error("Operands must be numbers."));
+        };
+        self.pop();
+        *self.peek_mut(0) = Value::Double(opcode.arithmetic(left, right).unwrap());
+        Ok(())
+    }
+
+    fn is_string(value: &Value) -> bool {
+        matches!(value, Value::Object(obj) if matches!(obj.value, HeapValue::String(_)))
+    }
+
     fn string_concat(&mut self) -> Result<(), InterpretError> {
-        if !matches!(
-            self.peek(0),
-            Value::Object(Obj {
-                value: HeapValue::String(_),
-                next: _,
-            })
-        ) || !matches!(
-            self.peek(1),
-            Value::Object(Obj {
-                value: HeapValue::String(_),
-                next: _,
-            })
-        ) {
+        if !Self::is_string(self.peek(0)) || !Self::is_string(self.peek(1)) {
             return Err(self.runtime_error("Operands must be strings."));
         }
         let a = self.pop();
         let b = self.pop();
         match (a, b) {
-            (
-                Value::Object(Obj {
-                    value: HeapValue::String(right),
-                    next: _,
-                }),
-                Value::Object(Obj {
-                    value: HeapValue::String(left),
-                    next: _,
-                }),
-            ) => {
+            (Value::Object(right), Value::Object(left)) => {
+                let left = left.as_string();
+                let right = right.as_string();
                 let mut new_string = String::with_capacity(left.len() + right.len());
                 let left_str = left.as_str();
                 let right_str = right.as_str();
                 new_string.push_str(left_str);
                 new_string.push_str(right_str);
 
-                let res = BoxedObjString::of(new_string);
-
-                let obj = self.create_object(HeapValue::String(res));
+                let obj = self.intern(&new_string);
                 self.push(Value::Object(obj));
                 Ok(())
             }
@@ -295,47 +1006,42 @@ impl VM {
         }
     }
 
-    #[cfg(debug_assertions)]
-    fn print_stack(&mut self) {
-        print!("        ");
-        for i in 0..self.stack_idx {
-            print!("[{}]", self.stack[i]);
-        }
-        println!();
-    }
-
+    // Records where the error happened. The stack is left as it is for
+    // `run_script` or `call` to unwind.
     fn runtime_error(&mut self, message: &str) -> InterpretError {
-        println!("{}", message);
+        let mut error = RuntimeError::new(message);
 
-        let chunk = self.chunk.borrow();
-        let start_ptr = &chunk.code[0] as *const u8;
-        let offset = (self.ip as usize) - (start_ptr as usize);
-        let line = chunk.lines[offset];
-        println!("line [{}] in script", line);
-        InterpretError::RuntimeError
-    }
-
-    fn create_object(&mut self, value: HeapValue) -> Obj {
-        let obj = Obj {
-            value,
-            next: self.objects.take(),
-        };
-        self.objects = Some(&obj);
-        obj
-    }
-
-    fn free_objects(&mut self) {
-        let maybe_obj = self.objects;
-        while let Some(obj_ref) = maybe_obj {
-            unsafe {
-                let obj = obj_ref.as_ref();
-            };
+        if let Some(frame) = self.frames.last_mut() {
+            frame.ip = self.ip;
+        }
+        for frame in self.frames.iter().rev() {
+            let function = frame.closure.as_closure().function;
+            let function = function.as_function();
+            let chunk = &function.chunk;
+            let start_ptr = chunk.code.as_ptr();
+            // The instruction pointer has already moved past the failing
+            // instruction.
+            let offset = (frame.ip as usize) - (start_ptr as usize) - 1;
+            // Only the innermost frame's span is of interest.
+            if error.frames.is_empty() {
+                error.span = Some(chunk.span(offset));
+                error.source = chunk.source.clone();
+            }
+            error.frames.push(StackFrame {
+                function: function
+                    .name
+                    .map(|name| name.as_string().as_str().to_owned()),
+                line: chunk.line(offset),
+            });
         }
+
+        InterpretError::RuntimeError(error)
     }
 }
 
-impl Drop for VM {
-    fn drop(&mut self) {
-        self.free_objects();
-    }
+fn clock_native(_vm: &mut VM, _args: &[host::Value]) -> Result<host::Value, RuntimeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| RuntimeError::new("System clock is set before the epoch."))?;
+    Ok(host::Value::Double(now.as_secs_f64()))
 }
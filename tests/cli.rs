@@ -0,0 +1,320 @@
+// Tests for the rlox command line beyond running scripts, which the
+// conformance tests cover.
+
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+fn rlox(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        // Keep REPL history out of the user's home directory.
+        .env("RLOX_HISTORY", temp_path("history"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run rlox");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli");
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+// Writes `source` to a temporary file named `name`, returning its path.
+fn script(name: &str, source: &str) -> String {
+    let path = temp_path(name);
+    fs::write(&path, source).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+const PROGRAM: &str = "\
+fun greet(name) {
+  return \"hello \" + name;
+}
+for (var i = 0; i < 2; i = i + 1) print greet(\"lox\");
+";
+
+#[test]
+fn compile_and_run_bytecode() {
+    let source = script("compile.lox", PROGRAM);
+    let bytecode = temp_path("compile.loxc");
+    let bytecode = bytecode.to_str().unwrap();
+
+    let output = rlox(&["compile", &source, "-o", bytecode], "");
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(fs::read(bytecode).unwrap().starts_with(b"LOXC"));
+
+    // Bytecode runs with `run` or directly, like source.
+    for args in [vec!["run", bytecode], vec![bytecode]] {
+        let output = rlox(&args, "");
+        assert_eq!(stdout(&output), "hello lox\nhello lox\n");
+        assert_eq!(output.status.code(), Some(0));
+    }
+}
+
+#[test]
+fn compile_defaults_to_loxc_extension() {
+    let source = script("default_output.lox", "print 1;");
+    let output = rlox(&["compile", &source], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(temp_path("default_output.loxc").exists());
+}
+
+#[test]
+fn compile_reports_errors() {
+    let source = script("compile_error.lox", "print 1 +;");
+    let output = rlox(&["compile", &source, "-o", temp_path("compile_error.loxc").to_str().unwrap()], "");
+    assert_eq!(stderr(&output), "[line 1] Error at ';': Expect expression.\n");
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn corrupt_bytecode_is_rejected() {
+    let source = script("corrupt.lox", PROGRAM);
+    let bytecode = temp_path("corrupt.loxc");
+    rlox(&["compile", &source, "-o", bytecode.to_str().unwrap()], "");
+    let mut bytes = fs::read(&bytecode).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&bytecode, &bytes).unwrap();
+
+    let output = rlox(&["run", bytecode.to_str().unwrap()], "");
+    assert_eq!(stderr(&output), "Checksum mismatch, the file is corrupt.\n");
+    assert_eq!(output.status.code(), Some(65));
+
+    let truncated = script("truncated.loxc", "LOXC");
+    let output = rlox(&["run", &truncated], "");
+    assert_eq!(output.status.code(), Some(65));
+}
+
+// A function with the given arity, upvalue count and code, up to its
+// constants.
+fn function_head(arity: u8, upvalue_count: u8, code: &[u8], constant_count: u32) -> Vec<u8> {
+    let mut bytes = vec![0, arity, upvalue_count];
+    bytes.extend((code.len() as u32).to_le_bytes());
+    bytes.extend(code);
+    bytes.extend(constant_count.to_le_bytes());
+    bytes
+}
+
+// What follows a function's constants: one debug run covering its code.
+fn function_tail() -> Vec<u8> {
+    let mut bytes = 1u32.to_le_bytes().to_vec();
+    bytes.extend([0u32, 1, 1, 0, 0].iter().flat_map(|n| n.to_le_bytes()));
+    bytes
+}
+
+// Writes a bytecode file holding `function` as the script, with a valid
+// header and checksum.
+fn bytecode_file(name: &str, function: &[u8]) -> String {
+    let mut bytes = b"LOXC".to_vec();
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(function);
+    let mut hash: u32 = 2166136261;
+    for byte in &bytes {
+        hash = (hash ^ *byte as u32).wrapping_mul(16777619);
+    }
+    bytes.extend(hash.to_le_bytes());
+    let path = temp_path(name);
+    fs::write(&path, bytes).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+// Writes a bytecode file holding just a top-level script with the given
+// arity, upvalue count and code.
+fn bytecode(name: &str, arity: u8, upvalue_count: u8, code: &[u8]) -> String {
+    let mut function = function_head(arity, upvalue_count, code, 0);
+    function.extend(function_tail());
+    bytecode_file(name, &function)
+}
+
+// Writes a bytecode file whose script has `depth` functions nested inside
+// it, each the only constant of the one around it.
+fn nested_bytecode(name: &str, depth: usize) -> String {
+    // Nil, Return.
+    let code = [3, 0];
+    let mut function = vec![];
+    for _ in 0..depth {
+        function.extend(function_head(0, 0, &code, 1));
+        function.push(5);
+    }
+    function.extend(function_head(0, 0, &code, 0));
+    for _ in 0..=depth {
+        function.extend(function_tail());
+    }
+    bytecode_file(name, &function)
+}
+
+#[test]
+fn invalid_bytecode_is_rejected() {
+    // Pop, Return: the pop empties the stack, leaving nothing to return.
+    let path = bytecode("invalid.loxc", 0, 0, &[16, 0]);
+    let output = rlox(&["run", &path], "");
+    assert_eq!(
+        stderr(&output),
+        "Invalid bytecode in script at offset 1: Return needs 1 values but the stack only has 0.\n"
+    );
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn script_with_upvalues_or_parameters_is_rejected() {
+    // GetUpvalue 0, Return: valid for a closure, but the script has no
+    // upvalues to read.
+    let path = bytecode("script_upvalue.loxc", 0, 1, &[27, 0, 0]);
+    let output = rlox(&["run", &path], "");
+    assert_eq!(
+        stderr(&output),
+        "Malformed bytecode: the top-level function captures variables\n"
+    );
+    assert_eq!(output.status.code(), Some(65));
+
+    // GetLocal 1, Return, reading a parameter the VM never passes.
+    let path = bytecode("script_parameter.loxc", 1, 0, &[20, 1, 0]);
+    let output = rlox(&["run", &path], "");
+    assert_eq!(stderr(&output), "Malformed bytecode: the top-level function takes parameters\n");
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn deeply_nested_functions_are_rejected() {
+    let path = nested_bytecode("nested_256.loxc", 256);
+    let output = rlox(&["run", &path], "");
+    assert_eq!(stderr(&output), "");
+    assert_eq!(output.status.code(), Some(0));
+
+    for depth in [257, 200_000] {
+        let path = nested_bytecode("nested_deep.loxc", depth);
+        let output = rlox(&["run", &path], "");
+        assert_eq!(stderr(&output), "Malformed bytecode: functions nested too deeply\n");
+        assert_eq!(output.status.code(), Some(65));
+    }
+
+    // The compiler refuses to write what the loader would reject.
+    let source = "fun f() {".repeat(257) + &"}".repeat(257);
+    let source = script("nested.lox", &source);
+    let output = rlox(&["compile", &source, "-o", temp_path("nested.loxc").to_str().unwrap()], "");
+    let errors = stderr(&output);
+    assert!(errors.starts_with("[line 1] Error at 'f': Too many nested functions.\n"), "{}", errors);
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn disasm_lists_every_function() {
+    let source = script("disasm.lox", PROGRAM);
+    let output = rlox(&["disasm", &source], "");
+    let listing = stdout(&output);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(listing.starts_with("== <script> ==\n0000    3 Closure "), "{}", listing);
+    assert!(listing.contains("\n== greet ==\n"), "{}", listing);
+    assert!(listing.contains("Constant            0 'hello '"), "{}", listing);
+    // Jumps point at labels placed before their targets.
+    assert!(listing.contains("JumpIfFalse      -> L"), "{}", listing);
+    assert!(listing.contains("\nL0:\n"), "{}", listing);
+}
+
+#[test]
+fn disasm_json() {
+    let source = script("disasm_json.lox", "var a = \"q\\\";");
+    let output = rlox(&["disasm", "--json", &source], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        concat!(
+            r#"{"functions":[{"name":null,"arity":0,"upvalue_count":0,"#,
+            r#""constants":[{"type":"string","value":"a"},{"type":"string","value":"q\\"}],"#,
+            r#""code":[{"offset":0,"line":1,"column":9,"opcode":"Constant","constant":1},"#,
+            r#"{"offset":2,"line":1,"column":13,"opcode":"DefineGlobal","constant":0},"#,
+            r#"{"offset":4,"line":1,"column":14,"opcode":"Nil"},"#,
+            r#"{"offset":5,"line":1,"column":14,"opcode":"Return"}]}]}"#,
+            "\n"
+        )
+    );
+}
+
+#[test]
+fn disasm_folds_constants() {
+    let source = script("fold.lox", "var a = 2;\nprint 60 * 60 ~/ 7 + a ** 2 ** 3;\n");
+    let output = rlox(&["disasm", &source], "");
+    assert_eq!(
+        stdout(&output),
+        "== <script> ==\n\
+         0000    1 Constant            1 '2'\n\
+         0002    | DefineGlobal        0 'a'\n\
+         0004    2 Constant            2 '514'\n\
+         0006    | GetGlobal           3 'a'\n\
+         0008    | Constant            4 '8'\n\
+         0010    | Power\n\
+         0011    | Add\n\
+         0012    | Print\n\
+         0013    3 Nil\n\
+         0014    | Return\n"
+    );
+}
+
+#[test]
+fn unknown_option_prints_usage() {
+    let output = rlox(&["--bogus"], "");
+    assert!(stdout(&output).starts_with("Usage: rlox"));
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn missing_file() {
+    let output = rlox(&[temp_path("missing.lox").to_str().unwrap()], "");
+    assert_eq!(stdout(&output), "File not found.\n");
+    assert_eq!(output.status.code(), Some(74));
+}
+
+#[test]
+fn repl_keeps_globals_and_echoes_expressions() {
+    let output = rlox(&[], "var a = 20;\na + 1\nprint a;\na;\n");
+    assert_eq!(stdout(&output), "> > 21\n> 20\n> 20\n> \n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn repl_continues_incomplete_input() {
+    let output = rlox(&[], "fun f(x) {\n  return x * 2;\n}\nf(\n4)\n\"two\nlines\"\n");
+    assert_eq!(stdout(&output), "> ... ... > ... 8\n> ... two\nlines\n> \n");
+}
+
+#[test]
+fn repl_reports_errors_and_carries_on() {
+    let output = rlox(&[], "1 +;\nnil();\n2\n");
+    assert_eq!(stdout(&output), "> > > 2\n> \n");
+    let errors = stderr(&output);
+    assert!(errors.starts_with("[line 1] Error at ';': Expect expression.\n"), "{}", errors);
+    assert!(errors.contains("Can only call functions and classes."), "{}", errors);
+}
+
+#[test]
+fn repl_commands() {
+    let library = script("library.lox", "fun double(x) { return x * 2; }\n1 + 1;");
+    let input = format!(":load {}\n:globals\ndouble(4)\n:disasm\n:time double(1)\n:reset\n:globals\n:bogus\n:quit\nprint 1;\n", library);
+    let output = rlox(&[], &input);
+    let out = stdout(&output);
+    // :load runs the file as a script, so its trailing expression is silent.
+    assert!(out.starts_with("> > clock = <native fn>\ndouble = <fn double>\n> 8\n> == <script> ==\n"), "{}", out);
+    assert!(out.contains("GetGlobal           0 'double'"), "{}", out);
+    assert!(out.contains("2\nTook "), "{}", out);
+    // After :reset only the natives are left, and :quit stops reading.
+    assert!(out.ends_with("> > clock = <native fn>\n> > "), "{}", out);
+    assert_eq!(stderr(&output), "Unknown command ':bogus'. Try :help.\n");
+}
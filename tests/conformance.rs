@@ -0,0 +1,174 @@
+// Runs every `.lox` file under tests/lox through the rlox binary and checks
+// its output against the expectations written in its comments:
+//
+//   print 1 + 2; // expect: 3
+//       a line of stdout
+//   a.b; // expect runtime error: Only instances have properties.
+//       the runtime error, raised on this line; exits with 70
+//   print; // Error at ';': Expect expression.
+//   // [line 3] Error at end: Expect '}' after block.
+//       a compile error on this line or the given one; exits with 65
+//
+// Scripts that compile are also run from precompiled bytecode, which must
+// behave the same, and with the garbage collector stressed.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+
+#[derive(Default)]
+struct Expectations {
+    stdout: Vec<String>,
+    compile_errors: Vec<String>,
+    // The message, and the line it's raised on.
+    runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Self {
+        let mut expectations = Expectations::default();
+        for (idx, line) in source.lines().enumerate() {
+            let line_number = idx + 1;
+            if let Some(idx) = line.find(EXPECT) {
+                expectations.stdout.push(line[idx + EXPECT.len()..].to_owned());
+            } else if let Some(idx) = line.find(EXPECT_RUNTIME_ERROR) {
+                let message = line[idx + EXPECT_RUNTIME_ERROR.len()..].to_owned();
+                expectations.runtime_error = Some((message, line_number));
+            } else if let Some(idx) = line.find("// [line ") {
+                expectations.compile_errors.push(line[idx + 3..].to_owned());
+            } else if let Some(idx) = line.find("// Error") {
+                let error = &line[idx + 3..];
+                expectations.compile_errors.push(format!("[line {}] {}", line_number, error));
+            }
+        }
+        expectations
+    }
+
+    fn exit_code(&self) -> i32 {
+        if !self.compile_errors.is_empty() {
+            65
+        } else if self.runtime_error.is_some() {
+            70
+        } else {
+            0
+        }
+    }
+}
+
+struct Output {
+    stdout: String,
+    stderr: String,
+    code: Option<i32>,
+}
+
+fn rlox(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .output()
+        .expect("failed to run rlox");
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        code: output.status.code(),
+    }
+}
+
+// Describes every way `output` differs from what's expected.
+fn check(expectations: &Expectations, output: &Output) -> Vec<String> {
+    let mut failures = vec![];
+
+    let stdout: Vec<&str> = output.stdout.lines().collect();
+    if stdout != expectations.stdout {
+        failures.push(format!(
+            "expected stdout:\n{}\ngot:\n{}",
+            expectations.stdout.join("\n"),
+            stdout.join("\n")
+        ));
+    }
+
+    let stderr: Vec<&str> = output.stderr.lines().collect();
+    if let Some((message, line)) = &expectations.runtime_error {
+        if stderr.first() != Some(&message.as_str()) {
+            failures.push(format!("expected runtime error '{}', got:\n{}", message, output.stderr));
+        }
+        // The innermost frame of the stack trace names the failing line.
+        let trace = format!("[line {}]", line);
+        if !stderr.iter().any(|line| line.starts_with(&trace)) {
+            failures.push(format!("expected a stack trace through {}, got:\n{}", trace, output.stderr));
+        }
+    } else if stderr != expectations.compile_errors {
+        failures.push(format!(
+            "expected stderr:\n{}\ngot:\n{}",
+            expectations.compile_errors.join("\n"),
+            output.stderr
+        ));
+    }
+
+    let code = expectations.exit_code();
+    if output.code != Some(code) {
+        failures.push(format!("expected exit code {}, got {:?}", code, output.code));
+    }
+    failures
+}
+
+fn lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            lox_files(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn conformance() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let mut files = vec![];
+    lox_files(&root, &mut files);
+    files.sort();
+    assert!(!files.is_empty(), "no tests found in {}", root.display());
+
+    let bytecode_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("conformance");
+    fs::create_dir_all(&bytecode_dir).unwrap();
+
+    let mut failures = vec![];
+    for file in &files {
+        let name = file.strip_prefix(&root).unwrap().display().to_string();
+        let source = fs::read_to_string(file).unwrap();
+        let expectations = Expectations::parse(&source);
+        let path = file.to_str().unwrap();
+
+        let mut runs = vec![("source", rlox(&[path])), ("--stress-gc", rlox(&["--stress-gc", path]))];
+        if expectations.compile_errors.is_empty() {
+            let bytecode = bytecode_dir.join(name.replace(['/', '\\'], "_") + "c");
+            let bytecode = bytecode.to_str().unwrap();
+            let compiled = rlox(&["compile", path, "-o", bytecode]);
+            if compiled.code != Some(0) {
+                failures.push(format!("{} (compile): failed with\n{}", name, compiled.stderr));
+                continue;
+            }
+            runs.push(("bytecode", rlox(&["run", bytecode])));
+        }
+
+        for (run, output) in runs {
+            for failure in check(&expectations, &output) {
+                failures.push(format!("{} ({}): {}", name, run, failure));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} conformance failures across {} tests:\n\n{}",
+        failures.len(),
+        files.len(),
+        failures.join("\n\n")
+    );
+}
@@ -0,0 +1,412 @@
+// Tests for using rlox as a library.
+
+use std::{cell::RefCell, io, rc::Rc};
+
+use rlox::{
+    is_incomplete, Chunk, CompileMode, ErrorCode, ErrorToken, InterpretError, LoadError,
+    PrintTracer, Repl, RuntimeError, Token, TokenType, Tracer, Value, VM,
+};
+
+fn runtime_error(result: Result<(), InterpretError>) -> RuntimeError {
+    match result {
+        Err(InterpretError::RuntimeError(error)) => error,
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn globals_persist_between_scripts() {
+    let mut vm = VM::new();
+    vm.interpret("var answer = 6 * 7; var name = \"lox\";").unwrap();
+    assert_eq!(vm.get_global("answer"), Some(Value::from(42.0)));
+    assert_eq!(vm.get_global("name").unwrap().as_str(), Some("lox"));
+    assert_eq!(vm.get_global("missing"), None);
+
+    vm.set_global("fromHost", &Value::from(true)).unwrap();
+    vm.interpret("var copy = fromHost and answer;").unwrap();
+    assert_eq!(vm.get_global("copy"), Some(Value::from(42.0)));
+
+    let names: Vec<String> = vm.globals().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, ["answer", "clock", "copy", "fromHost", "name"]);
+}
+
+#[test]
+fn values_outlive_garbage_collection() {
+    let mut vm = VM::new();
+    vm.set_stress_gc(true);
+    vm.interpret("var name = \"hello-host-string\";").unwrap();
+    let name = vm.get_global("name").unwrap();
+    vm.interpret("var other = \"x\" + \"y\"; name = nil;").unwrap();
+    assert_eq!(name.as_str(), Some("hello-host-string"));
+
+    // Strings passed in are copied onto the VM's heap.
+    vm.set_global("name", &name).unwrap();
+    vm.interpret("var copy = name + \"!\";").unwrap();
+    assert_eq!(vm.get_global("copy"), Some(Value::from("hello-host-string!")));
+}
+
+#[test]
+fn call_lox_functions() {
+    let mut vm = VM::new();
+    vm.interpret(
+        "fun add(a, b) { return a + b; }
+         class Pair { init(a, b) { this.sum = a + b; } }",
+    )
+    .unwrap();
+    let result = vm.call("add", &[Value::from(1.0), Value::from(2.0)]).unwrap();
+    assert_eq!(result, Value::from(3.0));
+
+    let greeting = Value::from("hello ");
+    let name = Value::from("world");
+    assert_eq!(vm.call("add", &[greeting, name]).unwrap().as_str(), Some("hello world"));
+
+    // Calling a class constructs an instance, which stays in the VM.
+    let pair = vm.call("Pair", &[Value::from(1.0), Value::from(2.0)]).unwrap();
+    assert_eq!(pair, Value::Object("Pair instance".to_owned()));
+    let error = vm.call("add", &[pair.clone(), pair]).unwrap_err();
+    assert_eq!(
+        error,
+        InterpretError::RuntimeError(RuntimeError::new("Can't pass Pair instance into Lox."))
+    );
+
+    let error = vm.call("missing", &[]).unwrap_err();
+    assert_eq!(
+        error,
+        InterpretError::RuntimeError(RuntimeError::new("Undefined variable 'missing'."))
+    );
+    let error = vm.call("add", &[Value::from(1.0)]).unwrap_err();
+    assert!(matches!(error, InterpretError::RuntimeError(e) if e.message == "Expected 2 arguments but got 1."));
+}
+
+fn twice(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    // Natives can call back into Lox.
+    let once = vm.call("callback", args).map_err(|_| RuntimeError::new("Callback failed."))?;
+    vm.call("callback", &[once]).map_err(|_| RuntimeError::new("Callback failed."))
+}
+
+fn fail(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    Err(RuntimeError::new("Native failure."))
+}
+
+// Calls `callback`, falling back to -1 if it fails.
+fn attempt(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(vm.call("callback", args).unwrap_or(Value::from(-1.0)))
+}
+
+#[test]
+fn natives_recover_from_failed_calls() {
+    let mut vm = VM::new();
+    vm.define_native("attempt", 1, attempt);
+    vm.define_native("twice", 1, twice);
+    vm.interpret(
+        "var captured;
+         fun callback(x) {
+           var local = x;
+           fun get() { return local; }
+           captured = get;
+           return x * nil;
+         }
+         fun outer(x) {
+           var before = 10;
+           return before + attempt(x) + before;
+         }
+         var result = outer(2);",
+    )
+    .unwrap();
+    assert_eq!(vm.get_global("result"), Some(Value::from(19.0)));
+    // A closure made by the failed call still sees the variable it captured.
+    vm.interpret("var seen = captured();").unwrap();
+    assert_eq!(vm.get_global("seen"), Some(Value::from(2.0)));
+
+    // A native passing the failure on reports it from its caller.
+    let error = runtime_error(vm.interpret("fun viaTwice() { return twice(1); }\nviaTwice();"));
+    assert_eq!(error.message, "Callback failed.");
+    let frames: Vec<_> = error.frames.iter().map(|f| (f.function.as_deref(), f.line)).collect();
+    assert_eq!(frames, [(Some("viaTwice"), 1), (None, 2)]);
+}
+
+#[test]
+fn natives() {
+    let mut vm = VM::new();
+    vm.define_native("twice", 1, twice);
+    vm.define_native("fail", 0, fail);
+    vm.interpret("fun callback(x) { return x * 3; } var result = twice(2);").unwrap();
+    assert_eq!(vm.get_global("result"), Some(Value::from(18.0)));
+
+    let error = runtime_error(vm.interpret("fun f() { fail(); }\nf();"));
+    assert_eq!(error.message, "Native failure.");
+    let frames: Vec<_> = error.frames.iter().map(|f| (f.function.as_deref(), f.line)).collect();
+    assert_eq!(frames, [(Some("f"), 1), (None, 2)]);
+}
+
+#[test]
+fn compile_diagnostics() {
+    let mut vm = VM::new();
+    let Err(InterpretError::CompileError(diagnostics)) =
+        vm.interpret("var x = 1;\n  print x +;\n{ var a = a; }\nprint \"é\" $;")
+    else {
+        panic!("expected compile errors");
+    };
+    let summary: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.code, d.token.clone(), d.line, d.column, d.span.clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (ErrorCode::UnexpectedToken, ErrorToken::Lexeme(";".to_owned()), 2, 12, 22..23),
+            (ErrorCode::UninitializedVariable, ErrorToken::Lexeme("a".to_owned()), 3, 11, 34..35),
+            (ErrorCode::InvalidToken, ErrorToken::Invalid, 4, 11, 50..51),
+        ]
+    );
+    assert_eq!(diagnostics[0].to_string(), "[line 2] Error at ';': Expect expression.");
+    assert_eq!(diagnostics[0].code.as_str(), "E0002");
+}
+
+#[test]
+fn runtime_error_snippet() {
+    let mut vm = VM::new();
+    let error = runtime_error(vm.interpret("var a = 1;\nvar b = \"b\";\nprint a +\tb * 3;"));
+    assert_eq!(
+        error.to_string(),
+        "Operands must be numbers.\n  |\n3 | print a +\tb * 3;\n  |          \t^^^^^\n[line 3] in script"
+    );
+}
+
+#[test]
+fn stack_limit() {
+    let mut vm = VM::new();
+    vm.set_stack_limit(8);
+    // Variables, so the sum isn't folded into a constant.
+    let error = runtime_error(vm.interpret("var n = 1; print n + (n + (n + (n + (n + (n + (n + (n + n)))))));"));
+    assert_eq!(error.message, "Stack overflow.");
+    // The VM is still usable afterwards.
+    vm.interpret("var ok = 1 + 2;").unwrap();
+    assert_eq!(vm.get_global("ok"), Some(Value::from(3.0)));
+}
+
+#[test]
+fn frame_limit() {
+    let mut vm = VM::new();
+    vm.interpret("fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }").unwrap();
+    let error = runtime_error(vm.interpret("depth(1000);"));
+    assert_eq!(error.message, "Stack overflow.");
+
+    vm.set_frame_limit(2000);
+    vm.interpret("var result = depth(1000);").unwrap();
+    assert_eq!(vm.get_global("result"), Some(Value::from(1000.0)));
+    // The stack limit still applies, whatever the frame limit.
+    vm.set_stack_limit(100);
+    let error = runtime_error(vm.interpret("depth(1000);"));
+    assert_eq!(error.message, "Stack overflow.");
+}
+
+#[derive(Default)]
+struct Counts {
+    tokens: Vec<TokenType>,
+    functions: Vec<String>,
+    instructions: usize,
+}
+
+// The VM owns its tracer, so the counts are shared with the test.
+struct CountingTracer(Rc<RefCell<Counts>>);
+
+impl Tracer for CountingTracer {
+    fn token(&mut self, token: &Token) {
+        self.0.borrow_mut().tokens.push(token.token_type);
+    }
+
+    fn code(&mut self, name: &str, _chunk: &Chunk) {
+        self.0.borrow_mut().functions.push(name.to_owned());
+    }
+
+    fn instruction(&mut self, _chunk: &Chunk, _offset: usize, _stack: &[Value]) {
+        self.0.borrow_mut().instructions += 1;
+    }
+}
+
+#[test]
+fn tracer() {
+    let counts = Rc::new(RefCell::new(Counts::default()));
+    let mut vm = VM::new();
+    vm.set_tracer(Some(Box::new(CountingTracer(counts.clone()))));
+    vm.interpret("fun f() {} f();").unwrap();
+
+    let counts = counts.borrow();
+    assert_eq!(counts.tokens.first(), Some(&TokenType::Fun));
+    assert_eq!(counts.tokens.last(), Some(&TokenType::EOF));
+    assert_eq!(counts.functions, ["f", "<script>"]);
+    // Closure, DefineGlobal, GetGlobal, Call, Nil and Return in f, Pop, Nil,
+    // Return.
+    assert_eq!(counts.instructions, 9);
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let mut vm = VM::new();
+    let bytecode = vm
+        .compile_bytecode("fun square(x) { return x * x; } var result = square(12);")
+        .unwrap();
+    let mut other = VM::new();
+    other.interpret_bytecode(&bytecode).unwrap();
+    assert_eq!(other.get_global("result"), Some(Value::from(144.0)));
+
+    assert_eq!(
+        other.interpret_bytecode(b"not bytecode"),
+        Err(InterpretError::LoadError(LoadError::NotBytecode))
+    );
+    let mut corrupt = bytecode.clone();
+    corrupt[10] ^= 1;
+    assert_eq!(
+        other.interpret_bytecode(&corrupt),
+        Err(InterpretError::LoadError(LoadError::ChecksumMismatch))
+    );
+}
+
+#[test]
+fn repl_mode() {
+    let mut vm = VM::with_output(io::sink(), io::sink());
+    vm.interpret_with_mode("var a = 1; a + 1", CompileMode::Repl).unwrap();
+    // Script mode still needs the semicolon.
+    assert!(matches!(
+        vm.interpret_with_mode("a + 1", CompileMode::Script),
+        Err(InterpretError::CompileError(_))
+    ));
+
+    assert!(is_incomplete("fun f() {"));
+    assert!(is_incomplete("print (1 +"));
+    assert!(is_incomplete("print \"unterminated"));
+    assert!(!is_incomplete("print 1;"));
+    assert!(!is_incomplete("print 1; }"));
+    assert!(!is_incomplete("print (7 ~/ 2);"));
+    assert!(!is_incomplete("print 1; // ("));
+    // Brackets in a comment don't count, even right after an operand.
+    assert!(is_incomplete("f(1, 2 // )"));
+}
+
+// A writer whose contents the test can read while the VM owns it.
+#[derive(Clone, Default)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+#[test]
+fn captured_output() {
+    let stdout = Buffer::default();
+    let stderr = Buffer::default();
+    let mut vm = VM::with_output(stdout.clone(), stderr.clone());
+    vm.interpret("print 1; print \"two\";").unwrap();
+    vm.interpret_with_mode("1 + 2", CompileMode::Repl).unwrap();
+    assert_eq!(stdout.contents(), "1\ntwo\n3\n");
+    // Only an expression statement at the top level is echoed, not the body
+    // of a trailing if or loop.
+    vm.interpret_with_mode("var x = 6; if (true) x = 5;", CompileMode::Repl).unwrap();
+    vm.interpret_with_mode("while (x > 4) x = x - 1;", CompileMode::Repl).unwrap();
+    vm.interpret_with_mode("for (;x < 6;) x = x + 1;", CompileMode::Repl).unwrap();
+    vm.interpret_with_mode("{ x; }", CompileMode::Repl).unwrap();
+    assert_eq!(stdout.contents(), "1\ntwo\n3\n");
+    // Nor can the body's semicolon be left off.
+    assert!(matches!(
+        vm.interpret_with_mode("if (true) x = 5", CompileMode::Repl),
+        Err(InterpretError::CompileError(_))
+    ));
+    assert!(matches!(
+        vm.interpret_with_mode("while (x > 4) x = x - 1", CompileMode::Repl),
+        Err(InterpretError::CompileError(_))
+    ));
+    assert_eq!(stderr.contents(), "");
+
+    let error = vm.interpret("print 4;\nprint nil + 1;").unwrap_err();
+    vm.report(&error);
+    let error = vm.interpret("print;").unwrap_err();
+    vm.report(&error);
+    assert_eq!(stdout.contents(), "1\ntwo\n3\n4\n");
+    assert_eq!(
+        stderr.contents(),
+        "Operands must be numbers.\n  |\n2 | print nil + 1;\n  |       ^^^^^^^\n[line 2] in script\n\
+         [line 1] Error at ';': Expect expression.\n"
+    );
+}
+
+#[test]
+fn discarded_output() {
+    let mut vm = VM::with_output(io::sink(), io::sink());
+    vm.interpret("var a = 1; print a;").unwrap();
+    assert_eq!(vm.get_global("a"), Some(Value::from(1.0)));
+}
+
+// A writer that always fails, like stdout closed by the other end of a
+// pipe.
+struct Closed;
+
+impl io::Write for Closed {
+    fn write(&mut self, _bytes: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_output_is_a_runtime_error() {
+    let mut vm = VM::with_output(Closed, io::sink());
+    let error = runtime_error(vm.interpret("print 1;"));
+    assert_eq!(error.message, "Couldn't write output: closed");
+}
+
+#[test]
+fn repl_output() {
+    let stdout = Buffer::default();
+    let stderr = Buffer::default();
+    let (out, err) = (stdout.clone(), stderr.clone());
+    let mut repl = Repl::new(move || VM::with_output(out.clone(), err.clone()));
+    repl.run("var a = 1;\na + 1\n:globals\n:bogus\nprint nil + 1;\n".as_bytes());
+    assert_eq!(stdout.contents(), "> > 2\n> a = 1\nclock = <native fn>\n> > > \n");
+    assert_eq!(
+        stderr.contents(),
+        "Unknown command ':bogus'. Try :help.\n\
+         Operands must be numbers.\n  |\n1 | print nil + 1;\n  |       ^^^^^^^\n[line 1] in script\n"
+    );
+
+    // The session ends quietly once its output is gone.
+    let mut repl = Repl::new(|| VM::with_output(Closed, io::sink()));
+    repl.run("print 1;\n".as_bytes());
+}
+
+#[test]
+fn print_tracer() {
+    let trace = Buffer::default();
+    let mut tracer = PrintTracer::with_output(trace.clone());
+    tracer.print_code = true;
+    let mut vm = VM::with_output(io::sink(), io::sink());
+    vm.set_tracer(Some(Box::new(tracer)));
+    vm.interpret("print 1;").unwrap();
+    assert!(trace.contents().starts_with("== <script> ==\n0000    1 Constant"), "{}", trace.contents());
+
+    // Failing to write a trace doesn't stop the program.
+    let mut tracer = PrintTracer::with_output(Closed);
+    tracer.trace_tokens = true;
+    tracer.print_code = true;
+    tracer.trace_exec = true;
+    let stdout = Buffer::default();
+    let mut vm = VM::with_output(stdout.clone(), io::sink());
+    vm.set_tracer(Some(Box::new(tracer)));
+    vm.interpret("print 1;").unwrap();
+    assert_eq!(stdout.contents(), "1\n");
+}